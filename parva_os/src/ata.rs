@@ -48,6 +48,7 @@ pub struct Bus {
     alternate_status_register: PortReadOnly<u8>, // Read-only alternate status register: same as status but does not clear intErrorupt flags.
     control_register: PortWriteOnly<u8>, // Write-only control register: used to send control signals like reset.
     drive_blockess_register: PortReadOnly<u8>, // Read-only drive address register (also called Drive Address or Drive Blockless register): rarely used.
+    sectors: [Option<u32>; 2], // Number of 512-byte sectors of each drive, found by IDENTIFY in `init` (None if no ATA drive).
 }
 
 impl Bus {
@@ -69,6 +70,7 @@ impl Bus {
             alternate_status_register: PortReadOnly::new(ctrl_base + 0),
             control_register: PortWriteOnly::new(ctrl_base + 0),
             drive_blockess_register: PortReadOnly::new(ctrl_base + 1),
+            sectors: [None; 2],
         }
     }
 
@@ -122,15 +124,17 @@ impl Bus {
     }
 
     // Spin-wait until Busy clears, or time out and reset if it hangs (>1s)
-    fn busy_loop(&mut self) {
+    fn busy_loop(&mut self) -> Result<(), ()> {
         self.wait();                             // initial short delay
         let start = time::uptime();         // timestamp in seconds
         while self.is_busy() {
             if time::uptime() - start > 1.0 {
-                return self.reset();             // give up and reset on hang
+                self.reset();                    // give up and reset on hang
+                return Err(());
             }
             spin_loop_hint();                    // CPU hint for busy-wait
         }
+        Ok(())
     }
 
     // Check the Busy bit in the status register
@@ -201,7 +205,9 @@ impl Bus {
             return None;
         }
 
-        self.busy_loop();                        // wait until ready or reset on hang
+        if self.busy_loop().is_err() {           // wait until ready or reset on hang
+            return None;
+        }
 
         // if non-zero LBA registers, device is ATAPI, not ATA
         if self.lba1() != 0 || self.lba2() != 0 {
@@ -231,25 +237,32 @@ impl Bus {
     }
 
     // Read exactly one 512-byte sector from the specified drive and LBA
-    pub fn read(&mut self, drive: u8, block: u32, buf: &mut [u8]) {
+    pub fn read(&mut self, drive: u8, block: u32, buf: &mut [u8]) -> Result<(), ()> {
         assert!(buf.len() == 512);
         self.setup(drive, block);
         self.write_command(Command::Read);
-        self.busy_loop();
+        self.busy_loop()?;
+        if self.is_Error() {
+            return Err(());
+        }
         // read 256 words and split into bytes
         for i in 0..256 {
             let data = self.read_data();
             buf[i * 2]     = data.get_bits(0..8) as u8;
             buf[i * 2 + 1] = data.get_bits(8..16) as u8;
         }
+        Ok(())
     }
 
     // Write exactly one 512-byte sector to the specified drive and LBA
-    pub fn write(&mut self, drive: u8, block: u32, buf: &[u8]) {
+    pub fn write(&mut self, drive: u8, block: u32, buf: &[u8]) -> Result<(), ()> {
         assert!(buf.len() == 512);
         self.setup(drive, block);
         self.write_command(Command::Write);
-        self.busy_loop();
+        self.busy_loop()?;
+        if self.is_Error() {
+            return Err(());
+        }
         // pack bytes into 256 words and write to data register
         for i in 0..256 {
             let mut data = 0u16;
@@ -257,7 +270,11 @@ impl Bus {
             data.set_bits(8..16, buf[i * 2 + 1] as u16);
             self.write_data(data);
         }
-        self.busy_loop();  // wait for final write completion
+        self.busy_loop()?;  // wait for final write completion
+        if self.is_Error() {
+            return Err(());
+        }
+        Ok(())
     }
 }
 
@@ -268,6 +285,11 @@ lazy_static! {
     pub static ref BUSES: Mutex<Vec<Bus>> = Mutex::new(Vec::new());
 }
 
+// Extract total sector count from IDENTIFY words 60 (low) and 61 (high)
+fn sector_count(identify_buf: &[u16; 256]) -> u32 {
+    (identify_buf[61] as u32) << 16 | (identify_buf[60] as u32)
+}
+
 // Given a count of 512-byte sectors, return (value, unit) as MB or GB.
 fn disk_size(sectors: u32) -> (u32, String) {
     let bytes = sectors * 512;
@@ -289,6 +311,13 @@ pub fn init() {
         let mut buses = BUSES.lock();
         buses.push(Bus::new(0, 0x1F0, 0x3F6, 14));
         buses.push(Bus::new(1, 0x170, 0x376, 15));
+
+        // Detect the drives once and keep their size: IDENTIFY resets the bus
+        for bus in buses.iter_mut() {
+            for drive in 0..2 {
+                bus.sectors[drive] = bus.identify_drive(drive as u8).map(|identify_buf| sector_count(&identify_buf));
+            }
+        }
     }
 
     // Uncomment to print
@@ -324,8 +353,7 @@ pub fn list() -> Vec<(u8, u8, String, String, u32, String)> {
                 }
                 let model = model.trim().to_string();
 
-                // Extract total sector count from words 60 (low) and 61 (high)
-                let sectors = (identify_buf[61] as u32) << 16 | (identify_buf[60] as u32);
+                let sectors = sector_count(&identify_buf);
                 let (size, unit) = disk_size(sectors);
 
                 result.push((bus_id as u8, drive as u8, model, serial, size, unit));
@@ -336,14 +364,27 @@ pub fn list() -> Vec<(u8, u8, String, String, u32, String)> {
     result
 }
 
+// Return the number of 512-byte sectors of a drive, or None if no ATA drive is present.
+// It was found when the drives were detected in `init`.
+pub fn sectors(bus: u8, drive: u8) -> Option<u32> {
+    let buses = BUSES.lock();
+    *buses.get(bus as usize)?.sectors.get(drive as usize)?
+}
+
 // Top-level read: dispatch to the appropriate Bus instance.
-pub fn read(bus: u8, drive: u8, block: u32, buf: &mut [u8]) {
+pub fn read(bus: u8, drive: u8, block: u32, buf: &mut [u8]) -> Result<(), ()> {
     let mut buses = BUSES.lock();
-    buses[bus as usize].read(drive, block, buf);
+    match buses.get_mut(bus as usize) {
+        Some(bus) => bus.read(drive, block, buf),
+        None => Err(()),
+    }
 }
 
 // Top-level write: dispatch to the appropriate Bus instance.
-pub fn write(bus: u8, drive: u8, block: u32, buf: &[u8]) {
+pub fn write(bus: u8, drive: u8, block: u32, buf: &[u8]) -> Result<(), ()> {
     let mut buses = BUSES.lock();
-    buses[bus as usize].write(drive, block, buf);
-}
\ No newline at end of file
+    match buses.get_mut(bus as usize) {
+        Some(bus) => bus.write(drive, block, buf),
+        None => Err(()),
+    }
+}
\ No newline at end of file
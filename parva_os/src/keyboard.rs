@@ -5,6 +5,8 @@ use x86_64::instructions::port::Port;
 use crate::interrupts::INPUT_QUEUE;
 use spin::MutexGuard;
 use alloc::collections::vec_deque::VecDeque;
+use alloc::string::String;
+use x86_64::instructions::interrupts;
 
 
 #[cfg(feature = "qwerty")]
@@ -20,6 +22,24 @@ pub fn init() {
     crate::interrupts::set_irq_handler(1, interrupt_handler);
 }
 
+// Wait for a line typed on the keyboard and return it without showing it,
+// for a passphrase asked before the window manager runs
+pub fn read_secret() -> String {
+    let mut secret = String::new();
+    loop {
+        let next = interrupts::without_interrupts(|| INPUT_QUEUE.lock().pop_front());
+        match next {
+            Some(b'\n') => return secret,
+            Some(0x08) => {
+                secret.pop();
+            }
+            Some(ch) if ch == b' ' || ch.is_ascii_graphic() => secret.push(ch as char),
+            Some(_) => {}
+            None => x86_64::instructions::hlt(),
+        }
+    }
+}
+
 fn read_scancode() -> u8 {
     let mut port = Port::new(0x60);
     unsafe { port.read() }
@@ -17,8 +17,10 @@ pub mod memory;
 pub mod allocator;
 pub mod window_manager;
 pub mod parva_fs;
+pub mod vfs;
 pub mod process;
 pub mod time;
+pub mod random;
 pub mod ata;
 pub mod keyboard;
 
@@ -33,7 +35,8 @@ pub fn init(boot_info: &'static BootInfo) {
     keyboard::init();
     memory::init(boot_info);
     ata::init();
-    parva_fs::ParvaFS::init();
+    parva_fs::init();
+    vfs::init();
 }
 
 #[alloc_error_handler]
@@ -75,6 +78,8 @@ pub enum QemuExitCode {
 pub fn exit_qemu(_exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
+    // Write the cached file system blocks before powering off
+    let _ = vfs::sync();
     unsafe {
         let mut port = Port::new(0x604);
         port.write(0x2000u16); // QEMU shutdown command
@@ -86,6 +91,9 @@ pub fn exit_qemu(_exit_code: QemuExitCode) {
 pub fn reboot() -> ! {
     use x86_64::instructions::port::Port;
 
+    // Write the cached file system blocks before resetting
+    let _ = vfs::sync();
+
     // The standard method on x86 is to write 0xFE to port 0x64
     unsafe {
         let mut port: Port<u8> = Port::new(0x64);
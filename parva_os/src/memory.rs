@@ -1,4 +1,6 @@
+use alloc::vec::Vec;
 use bootloader::bootinfo::{BootInfo, MemoryMap, MemoryRegionType};
+use spin::Mutex;
 use x86_64::structures::paging::mapper::MapperAllSizes;
 use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
@@ -6,6 +8,9 @@ use x86_64::{PhysAddr, VirtAddr};
 // NOTE: This static is mutable but it'll be changed only once during initialization
 static mut PHYS_MEM_OFFSET: u64 = 0;
 
+// Frames left after the heap is mapped, handed out to RAM disks
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
 pub fn init(boot_info: &'static BootInfo) {
     let mut memory_size = 0;
     for region in boot_info.memory_map.iter() {
@@ -21,6 +26,28 @@ pub fn init(boot_info: &'static BootInfo) {
     let mut mapper = unsafe { mapper(VirtAddr::new(PHYS_MEM_OFFSET)) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     crate::allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+// Take a free physical frame; it can be reached with `phys_to_virt` since all
+// physical memory is mapped
+pub fn alloc_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+}
+
+// Give back a frame taken with `alloc_frame`, which must no longer be used
+pub fn free_frame(frame: PhysFrame) {
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        allocator.freed.push(frame);
+    }
+}
+
+// Number of physical frames that `alloc_frame` can still return
+pub fn free_frames() -> usize {
+    match FRAME_ALLOCATOR.lock().as_ref() {
+        Some(allocator) => allocator.usable_frames().count().saturating_sub(allocator.next) + allocator.freed.len(),
+        None => 0,
+    }
 }
 
 pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
@@ -52,11 +79,12 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    freed: Vec<PhysFrame>, // frames given back, handed out again first
 }
 
 impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator { memory_map, next: 0 }
+        BootInfoFrameAllocator { memory_map, next: 0, freed: Vec::new() }
     }
 
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
@@ -70,6 +98,9 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.freed.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
@@ -1 +1,408 @@
-pub mod ParvaFS;
\ No newline at end of file
+// ParvaFS lives in the `parvafs` crate, which the host tool shares; this
+// module connects it to the kernel
+pub use parvafs::{cache, device, fsck, journal, partition, ParvaFS};
+
+pub mod ramdisk;
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::{ata, keyboard, print, println, process, random, time};
+use crate::vfs::{self, Entry, FileKind, FileSystem, Metadata, Usage};
+use device::BlockDevice;
+use partition::{Partition, PartitionDevice, Scheme};
+use ParvaFS::{BlockBitmap, Dir, DirEntry, File, FileType, FsError, Platform, Superblock, Volume};
+
+// An ATA drive, addressed by bus and drive number
+pub struct AtaDevice {
+    bus: u8,
+    dsk: u8,
+}
+
+impl AtaDevice {
+    pub fn new(bus: u8, dsk: u8) -> Self {
+        Self { bus, dsk }
+    }
+}
+
+impl BlockDevice for AtaDevice {
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        ata::read(self.bus, self.dsk, addr, buf).map_err(|_| FsError::Io)
+    }
+
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError> {
+        ata::write(self.bus, self.dsk, addr, buf).map_err(|_| FsError::Io)
+    }
+
+    fn block_count(&self) -> u32 {
+        ata::sectors(self.bus, self.dsk).unwrap_or(0)
+    }
+
+    // PIO writes are complete when `ata::write` returns
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+// A ParvaFS volume in the mount table of the VFS
+pub struct ParvaMount {
+    volume: Volume,
+}
+
+impl ParvaMount {
+    pub fn new(volume: Volume) -> Self {
+        Self { volume }
+    }
+}
+
+// The stored size of a compressed file is read from its first block
+fn metadata(entry: &DirEntry) -> Result<Metadata, FsError> {
+    Ok(Metadata {
+        kind: match entry.kind() {
+            FileType::Dir => FileKind::Dir,
+            FileType::File => FileKind::File,
+            FileType::Symlink => FileKind::Symlink,
+        },
+        size: entry.size() as usize,
+        stored: entry.stored_size()? as usize,
+        compressed: entry.is_compressed(),
+        links: entry.links(),
+        created: entry.created(),
+        modified: entry.modified(),
+        accessed: entry.accessed(),
+    })
+}
+
+impl FileSystem for ParvaMount {
+    fn kind(&self) -> &'static str {
+        "parvafs"
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Metadata, FsError> {
+        self.volume.run(|| metadata(&ParvaFS::stat(path)?))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<Entry>, FsError> {
+        self.volume.run(|| {
+            let entries = Dir::open(path)?.read()?;
+            entries.map(|entry| Ok(Entry { name: entry.name(), metadata: metadata(&entry)? })).collect()
+        })
+    }
+
+    fn read_at(&mut self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.volume.run(|| File::open(path)?.read_at(offset, buf))
+    }
+
+    fn write_at(&mut self, path: &str, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        self.volume.run(|| File::open(path)?.write_at(offset, buf))
+    }
+
+    fn write(&mut self, path: &str, buf: &[u8]) -> Result<(), FsError> {
+        self.volume.run(|| File::open(path)?.write(buf))
+    }
+
+    fn truncate(&mut self, path: &str, len: usize) -> Result<(), FsError> {
+        self.volume.run(|| File::open(path)?.truncate(len))
+    }
+
+    fn link(&mut self, src: &str, dst: &str) -> Result<(), FsError> {
+        self.volume.run(|| ParvaFS::link(src, dst))
+    }
+
+    fn symlink(&mut self, target: &str, path: &str) -> Result<(), FsError> {
+        self.volume.run(|| ParvaFS::symlink(target, path))
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, FsError> {
+        self.volume.run(|| ParvaFS::read_link(path))
+    }
+
+    fn set_compressed(&mut self, path: &str, compressed: bool) -> Result<(), FsError> {
+        self.volume.run(|| File::open(path)?.set_compressed(compressed))
+    }
+
+    fn compact(&mut self, path: &str) -> Result<usize, FsError> {
+        self.volume.run(|| Dir::open(path)?.compact())
+    }
+
+    fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        self.volume.run(|| File::create(path).map(|_| ()))
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        self.volume.run(|| Dir::create(path).map(|_| ()))
+    }
+
+    fn remove_file(&mut self, path: &str) -> Result<(), FsError> {
+        self.volume.run(|| File::delete(path))
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        self.volume.run(|| Dir::delete(path))
+    }
+
+    fn remove_all(&mut self, path: &str) -> Result<(), FsError> {
+        self.volume.run(|| Dir::delete_all(path))
+    }
+
+    fn rename(&mut self, src: &str, dst: &str) -> Result<(), FsError> {
+        self.volume.run(|| ParvaFS::rename(src, dst))
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        self.volume.run(ParvaFS::sync)
+    }
+
+    fn usage(&mut self) -> Result<Usage, FsError> {
+        self.volume.run(|| {
+            let sb = ParvaFS::superblock()?;
+            Ok(Usage { block_size: sb.block_size(), total: sb.data_count(), free: BlockBitmap::free_count()? })
+        })
+    }
+
+    fn volume(&self) -> Option<&Volume> {
+        Some(&self.volume)
+    }
+}
+
+// Name of an ATA drive in the mount table and in /dev, or of its partition
+// `part` when it is not 0
+pub fn ata_source(bus: u8, dsk: u8, part: u8) -> String {
+    if part == 0 {
+        format!("ata{}.{}", bus, dsk)
+    } else {
+        format!("ata{}.{}p{}", bus, dsk, part)
+    }
+}
+
+// Bus, drive and partition of a name given by `ata_source`
+pub fn parse_ata_source(source: &str) -> Option<(u8, u8, u8)> {
+    let source = source.strip_prefix("ata")?;
+    let (drive, part) = match source.find('p') {
+        Some(i) => (&source[..i], source[i + 1..].parse::<u8>().ok().filter(|&n| n > 0)?),
+        None => (source, 0),
+    };
+    let mut numbers = drive.splitn(2, '.').map(|n| n.parse::<u8>().ok().filter(|&n| n < 2));
+    Some((numbers.next()??, numbers.next()??, part))
+}
+
+// Whether two sources share blocks: they are the same, or a drive and one of
+// its partitions
+pub fn sources_overlap(a: &str, b: &str) -> bool {
+    a == b || a.starts_with(&format!("{}p", b)) || b.starts_with(&format!("{}p", a))
+}
+
+fn is_mounted(source: &str) -> bool {
+    vfs::mounts().iter().any(|mount| sources_overlap(&mount.source, source))
+}
+
+// The partitions of an ATA drive, none if it has no partition table
+pub fn ata_partitions(bus: u8, dsk: u8) -> Result<Vec<Partition>, FsError> {
+    let table = partition::read(&mut AtaDevice::new(bus, dsk))?;
+    Ok(table.map_or(Vec::new(), |table| table.partitions()))
+}
+
+// An ATA drive, or its partition `part` as ParvaFS sees it
+fn ata_device(bus: u8, dsk: u8, part: u8) -> Result<Box<dyn BlockDevice>, FsError> {
+    let disk = Box::new(AtaDevice::new(bus, dsk));
+    if part == 0 {
+        return Ok(disk);
+    }
+    let partition = ata_partitions(bus, dsk)?.into_iter().find(|p| p.index == part).ok_or(FsError::NotFound)?;
+    Ok(Box::new(PartitionDevice::new(disk, &partition)))
+}
+
+// Mount the ParvaFS volume of an ATA drive, or of one of its partitions, at `path`
+pub fn mount_ata(bus: u8, dsk: u8, part: u8, path: &str) -> Result<(), FsError> {
+    let source = ata_source(bus, dsk, part);
+    vfs::check_mount(path, &source)?;
+    let volume = Volume::mount(ata_device(bus, dsk, part)?)?;
+    vfs::mount(path, &source, Box::new(ParvaMount::new(volume)))
+}
+
+// Mount the encrypted ParvaFS volume of an ATA drive or partition at `path`,
+// with the key derived from `passphrase`
+pub fn unlock_ata(bus: u8, dsk: u8, part: u8, path: &str, passphrase: &str) -> Result<(), FsError> {
+    let source = ata_source(bus, dsk, part);
+    vfs::check_mount(path, &source)?;
+    let block_device = ParvaFS::unlock(ata_device(bus, dsk, part)?, passphrase)?;
+    let volume = Volume::mount(block_device)?;
+    vfs::mount(path, &source, Box::new(ParvaMount::new(volume)))
+}
+
+// Format an ATA drive or partition, encrypted if a passphrase is given, and
+// mount it at `path`, in place of the file system mounted there and of any
+// other mount that shares its blocks. An encrypted volume needs RDRAND for its
+// salt, and fails with Unsupported without it.
+pub fn format_ata(bus: u8, dsk: u8, part: u8, path: &str, passphrase: Option<&str>) -> Result<(), FsError> {
+    let source = ata_source(bus, dsk, part);
+    let path = ParvaFS::realpath(path);
+    let block_device = ata_device(bus, dsk, part)?;
+    for mount in vfs::mounts() {
+        if mount.path == path || sources_overlap(&mount.source, &source) {
+            vfs::unmount(&mount.path)?;
+        }
+    }
+    let volume = match passphrase {
+        Some(passphrase) => {
+            // The salt comes from the hardware generator, not from the one of /dev/random
+            let mut salt = [0; 16];
+            random::fill_secure(&mut salt).map_err(|_| FsError::Unsupported)?;
+            Volume::format_encrypted(block_device, passphrase, salt)?
+        }
+        None => Volume::format(block_device)?,
+    };
+    vfs::mount(&path, &source, Box::new(ParvaMount::new(volume)))
+}
+
+// What formatting an ATA drive or partition would overwrite: a ParvaFS
+// volume, and on a whole drive a partition table whose partitions may hold
+// the blocks of the new volume
+pub fn ata_contents(bus: u8, dsk: u8, part: u8) -> Vec<String> {
+    let mut found = Vec::new();
+    if let Ok(mut block_device) = ata_device(bus, dsk, part) {
+        if let Ok(sb) = Superblock::read(&mut *block_device) {
+            let volume = if sb.is_encrypted() { "an encrypted ParvaFS volume" } else { "a ParvaFS volume" };
+            found.push(format!("{} (version {})", volume, sb.version()));
+        }
+    }
+    if part == 0 {
+        match partition::read(&mut AtaDevice::new(bus, dsk)) {
+            Ok(Some(table)) if !table.partitions().is_empty() => {
+                let scheme = if table.scheme == Scheme::Gpt { "a GPT" } else { "an MBR" };
+                found.push(format!("{} partition table with {} partitions", scheme, table.partitions().len()));
+            }
+            Err(FsError::BadTable) => found.push("a damaged GPT partition table".into()),
+            _ => {}
+        }
+    }
+    found
+}
+
+// A random GUID, of version 4
+fn random_guid() -> [u8; 16] {
+    let mut guid = [0; 16];
+    random::fill(&mut guid);
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+    guid
+}
+
+// Write an empty partition table on an ATA drive that is not mounted, which
+// hides what its partitions held
+pub fn label_ata(bus: u8, dsk: u8, scheme: Scheme) -> Result<(), FsError> {
+    if is_mounted(&ata_source(bus, dsk, 0)) {
+        return Err(FsError::Busy);
+    }
+    partition::create(&mut AtaDevice::new(bus, dsk), scheme, random_guid()).map(|_| ())
+}
+
+// Add a ParvaFS partition of `count` sectors to an ATA drive, or one that
+// fills its largest free space; NotFound if the drive has no partition table
+pub fn add_partition(bus: u8, dsk: u8, count: Option<u32>) -> Result<Partition, FsError> {
+    // A volume on the whole drive may use the sectors of the new partition
+    if vfs::mounts().iter().any(|mount| mount.source == ata_source(bus, dsk, 0)) {
+        return Err(FsError::Busy);
+    }
+    let mut disk = AtaDevice::new(bus, dsk);
+    let mut table = partition::read(&mut disk)?.ok_or(FsError::NotFound)?;
+    let partition = table.add(count, random_guid())?;
+    table.write(&mut disk)?;
+    Ok(partition)
+}
+
+// Remove partition `part` from the table of an ATA drive, unless it is mounted
+pub fn delete_partition(bus: u8, dsk: u8, part: u8) -> Result<Partition, FsError> {
+    if is_mounted(&ata_source(bus, dsk, part)) {
+        return Err(FsError::Busy);
+    }
+    let mut disk = AtaDevice::new(bus, dsk);
+    let mut table = partition::read(&mut disk)?.ok_or(FsError::NotFound)?;
+    let partition = table.remove(part)?;
+    table.write(&mut disk)?;
+    Ok(partition)
+}
+
+fn realtime() -> u64 {
+    time::realtime() as u64
+}
+
+// A drive or partition as the shell names it: `0:1`, or `0:1p2` for partition 2
+pub fn ata_drive(bus: u8, dsk: u8, part: u8) -> String {
+    if part == 0 {
+        format!("{}:{}", bus, dsk)
+    } else {
+        format!("{}:{}p{}", bus, dsk, part)
+    }
+}
+
+// Ask for the passphrase of an encrypted volume found on boot, up to three
+// times; an empty one leaves the volume locked
+fn unlock_on_boot(bus: u8, dsk: u8, part: u8, path: &str) -> Result<(), FsError> {
+    for _ in 0..3 {
+        print!("Passphrase for ATA {} (empty to skip): ", ata_drive(bus, dsk, part));
+        let passphrase = keyboard::read_secret();
+        println!();
+        if passphrase.is_empty() {
+            break;
+        }
+        match unlock_ata(bus, dsk, part, path, &passphrase) {
+            Err(FsError::BadPassphrase) => println!("Wrong passphrase"),
+            result => return result,
+        }
+    }
+    Err(FsError::Locked)
+}
+
+// On OS init: give ParvaFS the clock and the current directory, then probe
+// each ATA device for the MAGIC superblock and mount it: the first volume
+// found at the root, the next ones at /mnt/disk1, /mnt/disk2... A drive with
+// a partition table is probed through its ParvaFS partitions instead, so
+// that a volume is not found twice when a partition starts where the volume
+// of a whole drive would. An encrypted volume is mounted once its passphrase
+// is typed
+pub fn init() {
+    ParvaFS::set_platform(Platform { realtime, dir: process::dir });
+    let mut count = 0;
+    for bus in 0..2 {
+        for dsk in 0..2 {
+            if ata::sectors(bus, dsk).is_none() {
+                continue;
+            }
+            let parts: Vec<u8> = match ata_partitions(bus, dsk) {
+                Ok(partitions) if !partitions.is_empty() => {
+                    partitions.iter().filter(|p| p.is_parvafs()).map(|p| p.index).collect()
+                }
+                Ok(_) => vec![0],
+                Err(e) => {
+                    println!("ATA {}:{} partitions cannot be read: {}\n", bus, dsk, e);
+                    continue;
+                }
+            };
+            for part in parts {
+                let mut block_device = match ata_device(bus, dsk, part) {
+                    Ok(block_device) => block_device,
+                    Err(_) => continue,
+                };
+                if let Ok(sb) = Superblock::read(&mut *block_device) {
+                    println!("ParvaFS Superblock found in ATA {}\n", ata_drive(bus, dsk, part));
+                    let path = if count == 0 { "/".into() } else { format!("/mnt/disk{}", count) };
+                    let result = if sb.is_encrypted() {
+                        unlock_on_boot(bus, dsk, part, &path)
+                    } else {
+                        mount_ata(bus, dsk, part, &path)
+                    };
+                    match result {
+                        Ok(()) => count += 1,
+                        Err(FsError::Locked) => {
+                            println!("ATA {} left locked, mount it with its passphrase\n", ata_drive(bus, dsk, part))
+                        }
+                        Err(e) => println!("ParvaFS version {} volume cannot be mounted: {}\n", sb.version(), e),
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+// RAM disk: a block device held in physical frames, lost on reboot
+//
+// The frames come from the frame allocator rather than the heap, which is too
+// small for a disk, and are reached through the mapping of all physical
+// memory. A disk keeps its first MiB for the boot image; a RAM disk has no
+// boot image, so its frames only store the blocks from the superblock on and
+// the blocks before read as zeros.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use crate::{memory, vfs};
+use super::device::BlockDevice;
+use super::ParvaFS::{FsError, Volume, BLOCK_SIZE, SUPERBLOCK_ADDR};
+use super::ParvaMount;
+
+const SOURCE: &str = "ramdisk"; // name in the mount table
+const FRAME_SIZE: u32 = 4096;
+const BLOCKS_PER_FRAME: u32 = FRAME_SIZE / BLOCK_SIZE;
+
+// The RAM disk, created on demand; set once it is formatted and mounted
+static RAMDISK: Mutex<Option<RamDisk>> = Mutex::new(None);
+
+// Frames holding the blocks, given back to the frame allocator when dropped
+struct Frames(Vec<PhysFrame>);
+
+impl Drop for Frames {
+    fn drop(&mut self) {
+        for frame in self.0.drain(..) {
+            memory::free_frame(frame);
+        }
+    }
+}
+
+// A handle on the RAM disk; clones share the same blocks
+#[derive(Clone)]
+pub struct RamDisk {
+    frames: Arc<Frames>,
+}
+
+impl RamDisk {
+    // Size in bytes of the stored blocks
+    pub fn size(&self) -> usize {
+        self.frames.0.len() * FRAME_SIZE as usize
+    }
+
+    // Address of a stored block
+    fn block_ptr(&self, addr: u32, len: usize) -> Result<*mut u8, FsError> {
+        if len != BLOCK_SIZE as usize {
+            return Err(FsError::Io);
+        }
+        let i = addr - SUPERBLOCK_ADDR;
+        let frame = self.frames.0.get((i / BLOCKS_PER_FRAME) as usize).ok_or(FsError::Io)?;
+        let offset = (i % BLOCKS_PER_FRAME) * BLOCK_SIZE;
+        Ok(memory::phys_to_virt(frame.start_address() + offset as u64).as_mut_ptr())
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if addr < SUPERBLOCK_ADDR {
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+            return Ok(());
+        }
+        let src = self.block_ptr(addr, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) }
+        Ok(())
+    }
+
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError> {
+        if addr < SUPERBLOCK_ADDR {
+            return Err(FsError::Io);
+        }
+        let dst = self.block_ptr(addr, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len()) }
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        SUPERBLOCK_ADDR + self.frames.0.len() as u32 * BLOCKS_PER_FRAME
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+// Allocate a RAM disk with `size` bytes, rounded up to whole frames; its
+// frames are given back if it is dropped
+fn create(size: usize) -> Result<RamDisk, FsError> {
+    let count = (size + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+    if count == 0 || count > memory::free_frames() {
+        return Err(FsError::NoSpace);
+    }
+    let mut frames = Frames(Vec::with_capacity(count));
+    for _ in 0..count {
+        frames.0.push(memory::alloc_frame().ok_or(FsError::NoSpace)?);
+    }
+    Ok(RamDisk { frames: Arc::new(frames) })
+}
+
+// The RAM disk, if it was created
+pub fn get() -> Option<RamDisk> {
+    RAMDISK.lock().clone()
+}
+
+// Create the RAM disk, format it and mount it at `path`; on error its frames
+// are given back and there is still no RAM disk
+pub fn create_at(size: usize, path: &str) -> Result<(), FsError> {
+    let mut ramdisk = RAMDISK.lock();
+    if ramdisk.is_some() {
+        return Err(FsError::AlreadyExists);
+    }
+    vfs::check_mount(path, SOURCE)?;
+    let disk = create(size)?;
+    let volume = Volume::format(Box::new(disk.clone()))?;
+    vfs::mount(path, SOURCE, Box::new(ParvaMount::new(volume)))?;
+    *ramdisk = Some(disk);
+    Ok(())
+}
+
+// Mount the RAM disk again, after `umount`; its files are still there
+pub fn mount(path: &str) -> Result<(), FsError> {
+    let disk = get().ok_or(FsError::NotFound)?;
+    vfs::check_mount(path, SOURCE)?;
+    let volume = Volume::mount(Box::new(disk))?;
+    vfs::mount(path, SOURCE, Box::new(ParvaMount::new(volume)))
+}
@@ -0,0 +1,79 @@
+// Random numbers for /dev/random
+//
+// A xorshift64* generator, seeded at first use from the RDRAND instruction
+// when the CPU has it, mixed with the time stamp counter. It is fast and
+// good enough for tests and games, not for keys: those come from
+// `fill_secure`, which reads RDRAND directly.
+
+use core::arch::x86_64::{__cpuid, _rdrand64_step};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::time;
+
+lazy_static! {
+    static ref STATE: Mutex<u64> = Mutex::new(seed());
+}
+
+// CPUID leaf 1 sets bit 30 of ECX when RDRAND is available
+fn has_rdrand() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 30) != 0 }
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut val = 0;
+    if _rdrand64_step(&mut val) == 1 {
+        Some(val)
+    } else {
+        None
+    }
+}
+
+fn seed() -> u64 {
+    let mut seed = time::rdtsc();
+    if has_rdrand() {
+        if let Some(val) = unsafe { rdrand() } {
+            seed ^= val;
+        }
+    }
+    // The state of xorshift must never be zero
+    if seed == 0 {
+        0x9E37_79B9_7F4A_7C15
+    } else {
+        seed
+    }
+}
+
+// Return 64 random bits
+pub fn get_u64() -> u64 {
+    let mut x = STATE.lock();
+    *x ^= *x >> 12;
+    *x ^= *x << 25;
+    *x ^= *x >> 27;
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+// Attempts of RDRAND before giving up, as Intel advises for a CPU that is
+// short of entropy
+const RDRAND_RETRIES: usize = 10;
+
+// Fill `buf` with random bytes straight from RDRAND, for keys and salts;
+// fails if the CPU has no RDRAND or it keeps failing
+pub fn fill_secure(buf: &mut [u8]) -> Result<(), ()> {
+    if !has_rdrand() {
+        return Err(());
+    }
+    for chunk in buf.chunks_mut(8) {
+        let val = (0..RDRAND_RETRIES).find_map(|_| unsafe { rdrand() }).ok_or(())?;
+        chunk.copy_from_slice(&val.to_le_bytes()[..chunk.len()]);
+    }
+    Ok(())
+}
+
+// Fill `buf` with random bytes
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = get_u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
@@ -1,5 +1,6 @@
 use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
 use uart_16550::SerialPort; // Imports the 'SerialPort' type, which provides an interface for working with serial communication over the UART 16550 protocol
 
 // Initialize something only once (in this case initialize SerialPort)
@@ -11,6 +12,28 @@ lazy_static! {
     };
 }
 
+// COM1 registers: a received byte is read from the data register once bit 0
+// of the line status register is set
+const DATA_PORT: u16 = 0x3F8;
+const LINE_STATUS_PORT: u16 = 0x3F8 + 5;
+
+// Return a byte received on the serial port, without waiting for one
+pub fn read_byte() -> Option<u8> {
+    let _serial = SERIAL1.lock();
+    let mut status: Port<u8> = Port::new(LINE_STATUS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        if status.read() & 1 == 0 {
+            return None;
+        }
+        Some(data.read())
+    }
+}
+
+pub fn write_byte(byte: u8) {
+    SERIAL1.lock().send(byte);
+}
+
 // To print stuff directly on the terminal instead of using the QEMU screen
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
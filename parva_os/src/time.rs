@@ -1,3 +1,4 @@
+use core::fmt;
 use core::sync::atomic::{spin_loop_hint, AtomicUsize, AtomicU64, Ordering};
 use x86_64::instructions::hlt;
 use x86_64::instructions::interrupts;
@@ -58,7 +59,7 @@ pub fn halt() {
 }
 
 // Read the CPU’s timestamp counter.
-fn rdtsc() -> u64 {
+pub fn rdtsc() -> u64 {
     unsafe {
         // Ensure all prior instructions complete before reading TSC
         core::arch::x86_64::_mm_lfence();
@@ -145,6 +146,56 @@ fn days_since_epoch(year: u16, month: u8, day: u8) -> u64 {
     days + day as u64 - 1
 }
 
+// Convert a Unix timestamp (seconds) back into a calendar date & time.
+pub fn from_timestamp(timestamp: u64) -> RtcTime {
+    let mut days = timestamp / 86400;
+    let secs = timestamp % 86400;
+
+    let mut year = 1970;
+    loop {
+        let n = if is_leap_year(year) { 366 } else { 365 };
+        if days < n {
+            break;
+        }
+        days -= n;
+        year += 1;
+    }
+
+    // Days in each month (non-leap)
+    let month_days = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    let mut month = 1;
+    loop {
+        let mut n = month_days[month as usize - 1];
+        if month == 2 && is_leap_year(year) {
+            n += 1;
+        }
+        if days < n {
+            break;
+        }
+        days -= n;
+        month += 1;
+    }
+
+    RtcTime {
+        year,
+        month,
+        day:    days as u8 + 1,
+        hour:   (secs / 3600) as u8,
+        minute: (secs / 60 % 60) as u8,
+        second: (secs % 60) as u8,
+    }
+}
+
+// Format as "YYYY-MM-DD HH:MM:SS".
+impl fmt::Display for RtcTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
 // Returns the approximate uptime in seconds (fractional).
 pub fn uptime() -> f64 {
     ticks() as f64 * time_between_ticks()
@@ -0,0 +1,250 @@
+// /dev: device files, to use devices through the file API
+//
+// `null` reads empty and drops what is written, `zero` reads zeros, `random`
+// reads random bytes and `ttyS0` reads and writes the serial port. Each disk
+// has a node, `ata<bus>.<drive>` or `ramdisk`, that reads and writes its raw
+// blocks, and each partition of an ATA drive one too, `ata<bus>.<drive>p<n>`;
+// a disk cannot be written while a file system on its blocks is mounted.
+//
+// Finding the disks takes an IDENTIFY command per drive and a read of each
+// partition table, so the list of disk nodes is built when /dev is mounted
+// and again each time it is listed; a disk missing from it is looked up
+// alone, from its name. A partition changed by `fdisk` is seen once /dev is
+// listed again.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::parva_fs::{self, ramdisk, AtaDevice};
+use crate::parva_fs::device::BlockDevice;
+use crate::parva_fs::partition::PartitionDevice;
+use crate::parva_fs::ParvaFS::{FsError, BLOCK_SIZE};
+use crate::{ata, random, serial};
+use super::{Entry, FileKind, FileSystem, Metadata};
+
+enum Node {
+    Null,
+    Zero,
+    Random,
+    Serial,
+    Disk(usize), // index in DevFs::disks
+}
+
+// A disk node, with the size of the disk, which does not change
+struct Disk {
+    name: String,
+    device: Box<dyn BlockDevice>,
+    size: usize,
+}
+
+impl Disk {
+    fn new(name: String, device: Box<dyn BlockDevice>) -> Self {
+        let size = device.block_count() as usize * BLOCK_SIZE as usize;
+        Self { name, device, size }
+    }
+}
+
+// The disks present
+fn disks() -> Vec<Disk> {
+    let mut disks = Vec::new();
+    for bus in 0..2 {
+        for dsk in 0..2 {
+            if ata::sectors(bus, dsk).is_some() {
+                disks.push(Disk::new(parva_fs::ata_source(bus, dsk, 0), Box::new(AtaDevice::new(bus, dsk))));
+                for partition in parva_fs::ata_partitions(bus, dsk).unwrap_or_default() {
+                    let disk = PartitionDevice::raw(Box::new(AtaDevice::new(bus, dsk)), &partition);
+                    disks.push(Disk::new(parva_fs::ata_source(bus, dsk, partition.index), Box::new(disk)));
+                }
+            }
+        }
+    }
+    if let Some(disk) = ramdisk::get() {
+        disks.push(Disk::new("ramdisk".to_string(), Box::new(disk)));
+    }
+    disks
+}
+
+// The disk with node name `name`, if it is present
+fn find_disk(name: &str) -> Option<Disk> {
+    if name == "ramdisk" {
+        return ramdisk::get().map(|disk| Disk::new(name.to_string(), Box::new(disk)));
+    }
+    let (bus, dsk, part) = parva_fs::parse_ata_source(name)?;
+    ata::sectors(bus, dsk)?;
+    let device: Box<dyn BlockDevice> = if part == 0 {
+        Box::new(AtaDevice::new(bus, dsk))
+    } else {
+        let partition = parva_fs::ata_partitions(bus, dsk).ok()?.into_iter().find(|p| p.index == part)?;
+        Box::new(PartitionDevice::raw(Box::new(AtaDevice::new(bus, dsk)), &partition))
+    };
+    Some(Disk::new(name.to_string(), device))
+}
+
+// Disks have the size of their blocks, the other devices an empty size
+fn file_metadata(size: usize) -> Metadata {
+    Metadata { kind: FileKind::File, size, stored: 0, compressed: false, links: 1, created: 0, modified: 0, accessed: 0 }
+}
+
+// Read the bytes of a disk from `offset`, up to its end
+fn read_disk(disk: &mut Disk, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    let bs = BLOCK_SIZE as usize;
+    let len = buf.len().min(disk.size.saturating_sub(offset));
+    let mut block = vec![0; bs];
+    let mut done = 0;
+    while done < len {
+        let pos = offset + done;
+        let n = (bs - pos % bs).min(len - done);
+        disk.device.read_block((pos / bs) as u32, &mut block)?;
+        buf[done..done + n].copy_from_slice(&block[pos % bs..pos % bs + n]);
+        done += n;
+    }
+    Ok(len)
+}
+
+// Write bytes to a disk from `offset`, up to its end; blocks that are only
+// partly written are read first
+fn write_disk(disk: &mut Disk, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+    let bs = BLOCK_SIZE as usize;
+    let size = disk.size;
+    if offset >= size && !buf.is_empty() {
+        return Err(FsError::NoSpace);
+    }
+    let len = buf.len().min(size.saturating_sub(offset));
+    let mut block = vec![0; bs];
+    let mut done = 0;
+    while done < len {
+        let pos = offset + done;
+        let n = (bs - pos % bs).min(len - done);
+        let addr = (pos / bs) as u32;
+        if n < bs {
+            disk.device.read_block(addr, &mut block)?;
+        }
+        block[pos % bs..pos % bs + n].copy_from_slice(&buf[done..done + n]);
+        disk.device.write_block(addr, &block)?;
+        done += n;
+    }
+    disk.device.flush()?;
+    Ok(len)
+}
+
+pub struct DevFs {
+    disks: Vec<Disk>,
+}
+
+impl DevFs {
+    fn node(&mut self, path: &str) -> Result<Node, FsError> {
+        match path {
+            "/null" => Ok(Node::Null),
+            "/zero" => Ok(Node::Zero),
+            "/random" => Ok(Node::Random),
+            "/ttyS0" => Ok(Node::Serial),
+            _ => {
+                let name = &path[1..];
+                if let Some(i) = self.disks.iter().position(|disk| disk.name == name) {
+                    return Ok(Node::Disk(i));
+                }
+                let disk = find_disk(name).ok_or(FsError::NotFound)?;
+                self.disks.push(disk);
+                Ok(Node::Disk(self.disks.len() - 1))
+            }
+        }
+    }
+}
+
+// Mount the device files at `path`, at most once
+pub fn mount(path: &str) -> Result<(), FsError> {
+    super::mount(path, "dev", Box::new(DevFs { disks: disks() }))
+}
+
+impl FileSystem for DevFs {
+    fn kind(&self) -> &'static str {
+        "dev"
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Metadata, FsError> {
+        if path == "/" {
+            return Ok(Metadata::dir());
+        }
+        match self.node(path)? {
+            Node::Disk(i) => Ok(file_metadata(self.disks[i].size)),
+            _ => Ok(file_metadata(0)),
+        }
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<Entry>, FsError> {
+        if path != "/" {
+            self.node(path)?;
+            return Err(FsError::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        for name in &["null", "zero", "random", "ttyS0"] {
+            entries.push(Entry { name: name.to_string(), metadata: file_metadata(0) });
+        }
+        self.disks = disks();
+        for disk in &self.disks {
+            entries.push(Entry { name: disk.name.clone(), metadata: file_metadata(disk.size) });
+        }
+        Ok(entries)
+    }
+
+    fn read_at(&mut self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        if path == "/" {
+            return Err(FsError::IsADirectory);
+        }
+        match self.node(path)? {
+            Node::Null => Ok(0),
+            Node::Zero => {
+                buf.iter_mut().for_each(|b| *b = 0);
+                Ok(buf.len())
+            }
+            Node::Random => {
+                random::fill(buf);
+                Ok(buf.len())
+            }
+            // Only the bytes already received, so that reading never blocks
+            Node::Serial => {
+                let mut n = 0;
+                while n < buf.len() {
+                    match serial::read_byte() {
+                        Some(byte) => buf[n] = byte,
+                        None => break,
+                    }
+                    n += 1;
+                }
+                Ok(n)
+            }
+            Node::Disk(i) => read_disk(&mut self.disks[i], offset, buf),
+        }
+    }
+
+    fn write_at(&mut self, path: &str, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        if path == "/" {
+            return Err(FsError::IsADirectory);
+        }
+        match self.node(path)? {
+            Node::Null | Node::Zero => Ok(buf.len()),
+            Node::Random => Err(FsError::ReadOnly),
+            Node::Serial => {
+                buf.iter().for_each(|&b| serial::write_byte(b));
+                Ok(buf.len())
+            }
+            Node::Disk(i) => {
+                let disk = &mut self.disks[i];
+                if super::mounts().iter().any(|m| parva_fs::sources_overlap(&m.source, &disk.name)) {
+                    return Err(FsError::Busy);
+                }
+                write_disk(disk, offset, buf)
+            }
+        }
+    }
+
+    // Devices have a fixed size: truncating them does nothing, so that they
+    // can be written as a whole
+    fn truncate(&mut self, path: &str, _len: usize) -> Result<(), FsError> {
+        if path == "/" {
+            return Err(FsError::IsADirectory);
+        }
+        self.node(path).map(|_| ())
+    }
+}
@@ -0,0 +1,432 @@
+// Virtual file system: one tree of paths over several mounted file systems
+//
+// The mount table maps absolute paths to file systems. A path belongs to the
+// mount with the longest matching prefix and is passed to it relative to the
+// mount point, so "/tmp/notes" on a volume mounted at "/tmp" is "/notes".
+// Mount points show up as directories in the listing of their parent, even
+// when the parent file system has no such directory.
+
+pub mod dev;
+pub mod proc;
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::parva_fs::ParvaFS::{self, FsError, Volume};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+// What `stat` and `read_dir` tell about a file or directory; times are Unix
+// seconds, 0 when unknown. `stored` is what the content takes on disk, less
+// than `size` for a compressed file and 0 for files that are not on a disk.
+// `links` is the number of names of a file. `stat` follows symbolic links,
+// `read_dir` shows them as they are.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub size: usize,
+    pub stored: usize,
+    pub compressed: bool,
+    pub links: u32,
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+}
+
+impl Metadata {
+    // A directory with no size or times, for mount points
+    pub fn dir() -> Self {
+        Self { kind: FileKind::Dir, size: 0, stored: 0, compressed: false, links: 1, created: 0, modified: 0, accessed: 0 }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+// Space of a file system, in blocks
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub block_size: u32,
+    pub total: u32,
+    pub free: u32,
+}
+
+// A file system that can be mounted. Paths are absolute and relative to the
+// mount point. Only the reading methods are required: the others fail with
+// `ReadOnly` unless the file system implements them.
+pub trait FileSystem: Send {
+    // Type of the file system, shown in the mount table
+    fn kind(&self) -> &'static str;
+
+    fn stat(&mut self, path: &str) -> Result<Metadata, FsError>;
+    fn read_dir(&mut self, path: &str) -> Result<Vec<Entry>, FsError>;
+    fn read_at(&mut self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write_at(&mut self, _path: &str, _offset: usize, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Replace the content of a file
+    fn write(&mut self, path: &str, buf: &[u8]) -> Result<(), FsError> {
+        self.truncate(path, 0)?;
+        self.write_at(path, 0, buf).map(|_| ())
+    }
+
+    fn truncate(&mut self, _path: &str, _len: usize) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create_file(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn remove_file(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Remove an empty directory
+    fn remove_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Remove a directory and everything in it
+    fn remove_all(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Rename or move an entry within the file system
+    fn rename(&mut self, _src: &str, _dst: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Give a file another name within the file system
+    fn link(&mut self, _src: &str, _dst: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Create a symbolic link to `target`, a path of the same file system
+    fn symlink(&mut self, _target: &str, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    // The target of a symbolic link
+    fn read_link(&mut self, _path: &str) -> Result<String, FsError> {
+        Err(FsError::InvalidPath)
+    }
+
+    // Store a file compressed or not; reads and writes give the same bytes
+    fn set_compressed(&mut self, _path: &str, _compressed: bool) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    // Rewrite a directory without its deleted entries; returns the blocks freed
+    fn compact(&mut self, _path: &str) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    // Write what is held in memory to the device
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn usage(&mut self) -> Result<Usage, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    // The ParvaFS volume behind the file system, for `fsck` and the block cache
+    fn volume(&self) -> Option<&Volume> {
+        None
+    }
+}
+
+struct Mount {
+    path: String,
+    source: String,
+    kind: &'static str,
+    fs: Arc<Mutex<Box<dyn FileSystem>>>,
+}
+
+// A line of the mount table
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub path: String,
+    pub source: String,
+    pub kind: &'static str,
+}
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+}
+
+// Check whether `path` is `prefix` or lies below it
+fn is_under(path: &str, prefix: &str) -> bool {
+    prefix == "/" || path == prefix || path.starts_with(&format!("{}/", prefix))
+}
+
+// Find the file system holding `path`, and the path inside it. The mount
+// table is not locked while the file system works, so that it can look up
+// other paths.
+fn resolve(path: &str) -> Result<(Arc<Mutex<Box<dyn FileSystem>>>, String), FsError> {
+    let mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter()
+        .filter(|m| is_under(path, &m.path))
+        .max_by_key(|m| m.path.len())
+        .ok_or(FsError::NotMounted)?;
+    let rest = if mount.path == "/" { path } else { &path[mount.path.len()..] };
+    let rest = if rest.is_empty() { "/" } else { rest };
+    Ok((mount.fs.clone(), rest.to_string()))
+}
+
+// Run `f` on the file system holding `path`, with the path inside it
+fn with_fs<T, F>(path: &str, f: F) -> Result<T, FsError>
+where
+    F: FnOnce(&mut dyn FileSystem, &str) -> Result<T, FsError>,
+{
+    let (fs, rest) = resolve(&ParvaFS::realpath(path))?;
+    let mut fs = fs.lock();
+    f(&mut **fs, &rest)
+}
+
+// Names in the directory `path` that lead to mount points below it
+fn mount_children(path: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for mount in MOUNTS.lock().iter() {
+        if mount.path != path && is_under(&mount.path, path) {
+            let start = if path == "/" { 1 } else { path.len() + 1 };
+            let name = mount.path[start..].split('/').next().unwrap_or("");
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+// Fail for paths that hold a mount point, which cannot be removed or moved
+fn check_not_busy(path: &str) -> Result<(), FsError> {
+    if MOUNTS.lock().iter().any(|m| is_under(&m.path, path)) {
+        return Err(FsError::Busy);
+    }
+    Ok(())
+}
+
+// Fail if something is mounted at `path`, or if `source` (a device) is mounted;
+// callers check before they open the device
+pub fn check_mount(path: &str, source: &str) -> Result<(), FsError> {
+    let path = ParvaFS::realpath(path);
+    if MOUNTS.lock().iter().any(|m| m.path == path || m.source == source) {
+        return Err(FsError::Busy);
+    }
+    Ok(())
+}
+
+// Mount a file system at `path`
+pub fn mount(path: &str, source: &str, fs: Box<dyn FileSystem>) -> Result<(), FsError> {
+    check_mount(path, source)?;
+    let path = ParvaFS::realpath(path);
+    let kind = fs.kind();
+    let mut mounts = MOUNTS.lock();
+    mounts.push(Mount { path, source: source.to_string(), kind, fs: Arc::new(Mutex::new(fs)) });
+    Ok(())
+}
+
+// Mount the file systems of the kernel itself
+pub fn init() {
+    let _ = dev::mount("/dev");
+    let _ = proc::mount("/proc");
+}
+
+// Sync the file system mounted at `path` and remove it from the mount table
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = ParvaFS::realpath(path);
+    let fs = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts.iter().find(|m| m.path == path).ok_or(FsError::NotMounted)?;
+        mount.fs.clone()
+    };
+    fs.lock().sync()?;
+    MOUNTS.lock().retain(|m| m.path != path);
+    Ok(())
+}
+
+// The mount table, sorted by path
+pub fn mounts() -> Vec<MountInfo> {
+    let mut table: Vec<MountInfo> = MOUNTS
+        .lock()
+        .iter()
+        .map(|m| MountInfo { path: m.path.clone(), source: m.source.clone(), kind: m.kind })
+        .collect();
+    table.sort_by(|a, b| a.path.cmp(&b.path));
+    table
+}
+
+// Sync every mounted file system, and return the first error
+pub fn sync() -> Result<(), FsError> {
+    let filesystems: Vec<_> = MOUNTS.lock().iter().map(|m| m.fs.clone()).collect();
+    let mut res = Ok(());
+    for fs in filesystems {
+        let r = fs.lock().sync();
+        res = res.and(r);
+    }
+    res
+}
+
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    let path = ParvaFS::realpath(path);
+    match with_fs(&path, |fs, rest| fs.stat(rest)) {
+        Err(FsError::NotFound) | Err(FsError::NotMounted) if !mount_children(&path).is_empty() => Ok(Metadata::dir()),
+        res => res,
+    }
+}
+
+// List a directory, with the mount points right below it
+pub fn read_dir(path: &str) -> Result<Vec<Entry>, FsError> {
+    let path = ParvaFS::realpath(path);
+    let children = mount_children(&path);
+    let mut entries = match with_fs(&path, |fs, rest| fs.read_dir(rest)) {
+        Ok(entries) => entries,
+        Err(FsError::NotFound) | Err(FsError::NotMounted) if !children.is_empty() => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    entries.retain(|e| !children.contains(&e.name));
+    for name in children {
+        let child = if path == "/" { format!("/{}", name) } else { format!("{}/{}", path, name) };
+        let metadata = stat(&child).unwrap_or_else(|_| Metadata::dir());
+        entries.push(Entry { name, metadata });
+    }
+    Ok(entries)
+}
+
+pub fn read_at(path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    with_fs(path, |fs, rest| fs.read_at(rest, offset, buf))
+}
+
+// Read a whole file
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    with_fs(path, |fs, rest| {
+        let mut buf = vec![0; fs.stat(rest)?.size];
+        let n = fs.read_at(rest, 0, &mut buf)?;
+        buf.truncate(n);
+        Ok(buf)
+    })
+}
+
+pub fn write_at(path: &str, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+    with_fs(path, |fs, rest| fs.write_at(rest, offset, buf))
+}
+
+// Replace the content of a file
+pub fn write(path: &str, buf: &[u8]) -> Result<(), FsError> {
+    with_fs(path, |fs, rest| fs.write(rest, buf))
+}
+
+pub fn truncate(path: &str, len: usize) -> Result<(), FsError> {
+    with_fs(path, |fs, rest| fs.truncate(rest, len))
+}
+
+pub fn set_compressed(path: &str, compressed: bool) -> Result<(), FsError> {
+    with_fs(path, |fs, rest| fs.set_compressed(rest, compressed))
+}
+
+pub fn compact(path: &str) -> Result<usize, FsError> {
+    with_fs(path, |fs, rest| fs.compact(rest))
+}
+
+pub fn create_file(path: &str) -> Result<(), FsError> {
+    with_fs(path, |fs, rest| fs.create_file(rest))
+}
+
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    with_fs(path, |fs, rest| fs.create_dir(rest))
+}
+
+pub fn remove_file(path: &str) -> Result<(), FsError> {
+    with_fs(path, |fs, rest| fs.remove_file(rest))
+}
+
+pub fn remove_dir(path: &str) -> Result<(), FsError> {
+    check_not_busy(&ParvaFS::realpath(path))?;
+    with_fs(path, |fs, rest| fs.remove_dir(rest))
+}
+
+pub fn remove_all(path: &str) -> Result<(), FsError> {
+    check_not_busy(&ParvaFS::realpath(path))?;
+    with_fs(path, |fs, rest| fs.remove_all(rest))
+}
+
+// Rename or move an entry; both paths must be on the same file system
+pub fn rename(src: &str, dst: &str) -> Result<(), FsError> {
+    let src = ParvaFS::realpath(src);
+    let dst = ParvaFS::realpath(dst);
+    check_not_busy(&src)?;
+    check_not_busy(&dst)?;
+    let (fs, src_rest) = resolve(&src)?;
+    let (dst_fs, dst_rest) = resolve(&dst)?;
+    if !Arc::ptr_eq(&fs, &dst_fs) {
+        return Err(FsError::CrossDevice);
+    }
+    let mut fs = fs.lock();
+    fs.rename(&src_rest, &dst_rest)
+}
+
+// Give a file another name; both paths must be on the same file system
+pub fn link(src: &str, dst: &str) -> Result<(), FsError> {
+    let (fs, src_rest) = resolve(&ParvaFS::realpath(src))?;
+    let (dst_fs, dst_rest) = resolve(&ParvaFS::realpath(dst))?;
+    if !Arc::ptr_eq(&fs, &dst_fs) {
+        return Err(FsError::CrossDevice);
+    }
+    let mut fs = fs.lock();
+    fs.link(&src_rest, &dst_rest)
+}
+
+// Create a symbolic link at `path`. The target is resolved by the file system
+// of the link, so an absolute one starts at the mount point of that file system.
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    with_fs(path, |fs, rest| fs.symlink(target, rest))
+}
+
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    with_fs(path, |fs, rest| fs.read_link(rest))
+}
+
+// Space of the file system holding `path`
+pub fn usage(path: &str) -> Result<Usage, FsError> {
+    with_fs(path, |fs, _| fs.usage())
+}
+
+// Run `f` on the ParvaFS volume holding `path`
+pub fn with_volume<T, F>(path: &str, f: F) -> Result<T, FsError>
+where
+    F: FnOnce(&Volume) -> Result<T, FsError>,
+{
+    with_fs(path, |fs, _| match fs.volume() {
+        Some(volume) => f(volume),
+        None => Err(FsError::Unsupported),
+    })
+}
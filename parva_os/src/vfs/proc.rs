@@ -0,0 +1,137 @@
+// /proc: read-only files that show the state of the kernel
+//
+// The text of a file is generated each time it is read, so it is always
+// current. The size given by `stat` is that of the text generated then.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::parva_fs::ParvaFS::{self, FsError};
+use crate::{allocator, ata, memory, process, time};
+use super::{Entry, FileKind, FileSystem, Metadata};
+
+const DIRS: &[&str] = &["/", "/self"];
+
+const FILES: &[(&str, fn() -> String)] = &[
+    ("/uptime", uptime),
+    ("/ticks", ticks),
+    ("/meminfo", meminfo),
+    ("/disks", disks),
+    ("/mounts", mounts),
+    ("/self/dir", self_dir),
+    ("/self/env", self_env),
+];
+
+// Seconds since boot
+fn uptime() -> String {
+    format!("{:.6}\n", time::uptime())
+}
+
+// PIT interrupts since boot
+fn ticks() -> String {
+    format!("{}\n", time::ticks())
+}
+
+// Heap and physical memory, in bytes
+fn meminfo() -> String {
+    format!(
+        "HeapTotal: {} bytes\nHeapUsed:  {} bytes\nHeapFree:  {} bytes\nPhysFree:  {} bytes\n",
+        allocator::size(),
+        allocator::used(),
+        allocator::free(),
+        memory::free_frames() * 4096,
+    )
+}
+
+// One line per ATA drive: name, size, model and serial number
+fn disks() -> String {
+    let mut text = String::new();
+    for (bus, drive, model, serial, size, unit) in ata::list() {
+        text.push_str(&format!("ata{}.{} {} {} {} {}\n", bus, drive, size, unit, model, serial));
+    }
+    text
+}
+
+// One line per mount, like the `mount` command
+fn mounts() -> String {
+    let mut text = String::new();
+    for mount in super::mounts() {
+        text.push_str(&format!("{} {} {}\n", mount.source, mount.path, mount.kind));
+    }
+    text
+}
+
+fn self_dir() -> String {
+    format!("{}\n", process::dir())
+}
+
+// One `KEY=value` line per environment variable
+fn self_env() -> String {
+    let mut text = String::new();
+    for (key, val) in process::envs() {
+        text.push_str(&format!("{}={}\n", key, val));
+    }
+    text
+}
+
+fn generator(path: &str) -> Result<fn() -> String, FsError> {
+    FILES.iter().find(|(p, _)| *p == path).map(|(_, f)| *f).ok_or(FsError::NotFound)
+}
+
+fn file_metadata(size: usize) -> Metadata {
+    let now = time::realtime() as u64;
+    Metadata { kind: FileKind::File, size, stored: 0, compressed: false, links: 1, created: now, modified: now, accessed: now }
+}
+
+pub struct ProcFs;
+
+// Mount the kernel files at `path`, at most once
+pub fn mount(path: &str) -> Result<(), FsError> {
+    super::mount(path, "proc", Box::new(ProcFs))
+}
+
+impl FileSystem for ProcFs {
+    fn kind(&self) -> &'static str {
+        "proc"
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Metadata, FsError> {
+        if DIRS.contains(&path) {
+            return Ok(Metadata::dir());
+        }
+        let text = generator(path)?();
+        Ok(file_metadata(text.len()))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<Entry>, FsError> {
+        if !DIRS.contains(&path) {
+            generator(path)?;
+            return Err(FsError::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        for dir in DIRS.iter().filter(|d| **d != "/" && ParvaFS::dirname(d) == path) {
+            let name = ParvaFS::filename(dir).to_string();
+            entries.push(Entry { name, metadata: Metadata::dir() });
+        }
+        for (file, f) in FILES.iter().filter(|(p, _)| ParvaFS::dirname(p) == path) {
+            let name = ParvaFS::filename(file).to_string();
+            entries.push(Entry { name, metadata: file_metadata(f().len()) });
+        }
+        Ok(entries)
+    }
+
+    fn read_at(&mut self, path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        if DIRS.contains(&path) {
+            return Err(FsError::IsADirectory);
+        }
+        let text = generator(path)?();
+        let bytes = text.as_bytes();
+        if offset >= bytes.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(bytes.len() - offset);
+        buf[..n].copy_from_slice(&bytes[offset..offset + n]);
+        Ok(n)
+    }
+}
@@ -1,11 +1,13 @@
 use core::num;
 
-use alloc::{borrow::ToOwned, string::String, vec::Vec, vec};
+use alloc::{borrow::ToOwned, format, string::String, vec::Vec, vec};
 use x86_64::instructions::hlt;
 use crate::{
     vga::{Color, ColorCode, ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH}, 
     interrupts::INPUT_QUEUE, 
-    parva_fs::ParvaFS::{Dir, FileType, File}
+    parva_fs::{self, cache, fsck, ramdisk, AtaDevice, ParvaFS::{self, FsError}},
+    parva_fs::partition::{self, Scheme},
+    ata, process, time, vfs::{self, FileKind, Metadata}
 };
 
 const DESKTOP_BG: Color = Color::LightBlue;
@@ -23,6 +25,7 @@ pub struct Window {
     command_history: Vec<String>,
     current_line: usize,
     cursor_pos: usize,
+    prompt_len: usize,
     needs_redraw: bool,
     move_mode: bool,
     prev_x: usize,
@@ -33,22 +36,54 @@ pub struct Window {
     original_width: usize,     
     original_height: usize,    
     needs_desktop_redraw: bool,
+    hexdump: Option<HexDump>,
+    secret: Option<SecretPrompt>,
+    confirm: Option<Confirm>,
+}
+
+// A file shown by `hexdump`, one page at a time
+struct HexDump {
+    path: String,
+    offset: usize,
+    size: usize,
+}
+
+// A passphrase being typed, which is not shown, and what it is for
+struct SecretPrompt {
+    input: String,
+    purpose: Secret,
+}
+
+enum Secret {
+    Mount(u8, u8, u8, String),    // unlock an ATA drive or partition and mount it at the path
+    Format(Format),               // the passphrase of the volume `install` or `format` creates
+    FormatAgain(Format, String),  // the same, typed again to catch typos
+}
+
+// What is done to a drive once the user confirms it
+enum Confirm {
+    Format(Format),
+    Label(u8, u8, Scheme), // a new, empty partition table written by `fdisk`
+}
+
+// An ATA drive or partition that `install` or `format` erases once the user
+// confirms, and where its new volume is mounted
+struct Format {
+    bus: u8,
+    dsk: u8,
+    part: u8,
+    path: String,
+    encrypt: bool,
 }
 
 impl Window {
     pub fn new(name: String, x_pos: usize, y_pos: usize, width: usize, height: usize) -> Self {
-        let mut contents = vec![
+        let contents = vec![
             vec![ScreenChar::new(b' ', ColorCode::new(Color::White, Color::Black)); width];
             height - 1
         ];
-        
-        // Add initial prompt
-        let prompt = b"> ";
-        for (i, &ch) in prompt.iter().enumerate() {
-            contents[0][i] = ScreenChar::new(ch, ColorCode::new(Color::White, Color::Black));
-        }
 
-        Self {
+        let mut window = Self {
             contents,
             name,
             x_pos,
@@ -58,7 +93,8 @@ impl Window {
             input_buffer: String::new(),
             command_history: Vec::new(),
             current_line: 0,
-            cursor_pos: 2,  // Start after "> "
+            cursor_pos: 0,
+            prompt_len: 0,
             needs_redraw: true,
             move_mode: false,
             prev_x: x_pos,
@@ -69,7 +105,15 @@ impl Window {
             original_width: width,
             original_height: height,
             needs_desktop_redraw: false,
-        }
+            hexdump: None,
+            secret: None,
+            confirm: None,
+        };
+
+        // Add initial prompt
+        write_prompt(&mut window, 0);
+        window.cursor_pos = window.prompt_len;  // Start after the prompt
+        window
     }
 
     pub fn draw(&self, buffer: &mut Buffer2D) {
@@ -316,21 +360,15 @@ fn handle_input(window: &mut Window, ch: u8) {
                     for (i, row) in window.contents.iter().skip(start_line).enumerate() {
                         let copy_len = row.len().min(window.width);
                         new_contents[i][..copy_len].copy_from_slice(&row[..copy_len]);
-                        
-                        // Always ensure last line has prompt
-                        if i == target_lines - 1 {
-                            let prompt = b"> ";
-                            for (col, &ch) in prompt.iter().enumerate() {
-                                if col < window.width {
-                                    new_contents[i][col] = ScreenChar::new(ch, ColorCode::new(Color::White, Color::Black));
-                                }
-                            }
-                        }
                     }
                     
                     window.contents = new_contents;
                     window.current_line = target_lines.saturating_sub(1);
-                    window.cursor_pos = 2 + window.input_buffer.len().min(window.width - 2);
+
+                    // Always ensure last line has prompt
+                    write_prompt(window, window.current_line);
+                    window.cursor_pos = window.prompt_len
+                        + window.input_buffer.len().min(window.width - window.prompt_len);
                 } else {
                     // Save current state
                     window.original_x = window.x_pos;
@@ -368,7 +406,73 @@ fn handle_input(window: &mut Window, ch: u8) {
         return;
     }
 
+    // While `hexdump` waits at the end of a page, Space or Enter shows the next
+    // page and q or Escape stops it
+    if window.hexdump.is_some() {
+        if ch == b' ' || ch == b'\n' || ch == b'q' || ch == 0x1B {
+            // Replace the "More" line
+            for c in window.contents[window.current_line].iter_mut() {
+                *c = ScreenChar::new(b' ', ColorCode::new(Color::White, Color::Black));
+            }
+            window.current_line -= 1;
+            if ch == b' ' || ch == b'\n' {
+                show_hexdump_page(window);
+            } else {
+                window.hexdump = None;
+                add_new_line(window);
+                window.cursor_pos = window.prompt_len;
+            }
+        }
+        return;
+    }
+
+    // While a passphrase is asked, the keys typed are neither shown nor kept
+    // in the history; Enter uses it and Escape gives up
+    if let Some(prompt) = window.secret.as_mut() {
+        match ch {
+            b'\n' => {
+                if let Some(prompt) = window.secret.take() {
+                    use_secret(window, prompt);
+                }
+            }
+            0x1B => {
+                window.secret = None;
+                add_new_line(window);
+                window.cursor_pos = window.prompt_len;
+            }
+            0x08 => {
+                prompt.input.pop();
+            }
+            _ if ch == b' ' || ch.is_ascii_graphic() => prompt.input.push(ch as char),
+            _ => {}
+        }
+        return;
+    }
+
     match ch {
+        b'\n' if window.confirm.is_some() => {
+            // The line typed after `install`, `format` or `fdisk label` asked for confirmation
+            if let Some(confirm) = window.confirm.take() {
+                let answer = window.input_buffer.clone();
+                window.input_buffer.clear();
+                let confirmed = answer.trim() == "yes";
+                match confirm {
+                    Confirm::Format(_) if !confirmed => add_output_line(window, "Formatting cancelled"),
+                    Confirm::Format(target) if target.encrypt => {
+                        ask_secret(window, "New passphrase: ", Secret::Format(target));
+                        return;
+                    }
+                    Confirm::Format(target) => format_drive(window, &target, None),
+                    Confirm::Label(..) if !confirmed => add_output_line(window, "Partitioning cancelled"),
+                    Confirm::Label(bus, dsk, scheme) => match parva_fs::label_ata(bus, dsk, scheme) {
+                        Ok(()) => add_output_line(window, "Partition table written, add partitions with `fdisk <bus>:<drive> new`"),
+                        Err(e) => add_output_line(window, &format!("Error writing partition table: {}", e)),
+                    },
+                }
+                add_new_line(window);
+                window.cursor_pos = window.prompt_len;
+            }
+        }
         b'\n' => {
             // Process command
             let command = window.input_buffer.clone();
@@ -380,50 +484,264 @@ fn handle_input(window: &mut Window, ch: u8) {
                 if cmd == "crfile" {
                     // Create file command
                     if let Some(&filename) = parts.get(1) {
-                        let dir = Dir::root();
-                        if dir.create_file(filename).is_some() {
-                            add_output_line(window, "File created");
-                        } else {
-                            add_output_line(window, "Error creating file");
+                        match vfs::create_file(filename) {
+                            Ok(()) => add_output_line(window, "File created"),
+                            Err(e) => add_output_line(window, &format!("Error creating file: {}", e)),
                         }
                     } else {
                         add_output_line(window, "Usage: crfile <filename>");
                     }
                     add_new_line(window);
                     window.input_buffer.clear();
-                    window.cursor_pos = 2;
+                    window.cursor_pos = window.prompt_len;
                     return;
                 } else if cmd == "list" {
-                    // List directory contents
-                    let dir = Dir::root();
-                    for entry in dir.read() {
-                        let mut name = entry.name();
-                        if entry.is_dir() {
-                            name.push('/');
+                    // List directory contents (current directory by default),
+                    // with type, size and modification date when given "-l",
+                    // and the target of symbolic links
+                    let long = parts.get(1) == Some(&"-l");
+                    let pathname = parts.get(if long { 2 } else { 1 }).copied().unwrap_or(".");
+                    match vfs::read_dir(pathname) {
+                        Ok(entries) => {
+                            for entry in entries {
+                                let metadata = entry.metadata;
+                                let mut name = entry.name;
+                                if metadata.is_dir() {
+                                    name.push('/');
+                                }
+                                if long {
+                                    let kind = match metadata.kind {
+                                        FileKind::Dir => 'd',
+                                        FileKind::File => '-',
+                                        FileKind::Symlink => 'l',
+                                    };
+                                    if metadata.is_symlink() {
+                                        let target = vfs::read_link(&format!("{}/{}", pathname, name));
+                                        name = format!("{} -> {}", name, target.unwrap_or_else(|_| "?".to_owned()));
+                                    }
+                                    let line = format!(
+                                        "{} {:>8} {} {}",
+                                        kind, metadata.size, format_time(metadata.modified), name
+                                    );
+                                    add_output_line(window, &line);
+                                } else {
+                                    add_output_line(window, &name);
+                                }
+                            }
+                        }
+                        Err(e) => add_output_line(window, &format!("Error listing directory: {}", e)),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "mkdir" {
+                    // Create directory command
+                    if let Some(&pathname) = parts.get(1) {
+                        match vfs::create_dir(pathname) {
+                            Ok(()) => add_output_line(window, "Directory created"),
+                            Err(e) => add_output_line(window, &format!("Error creating directory: {}", e)),
                         }
-                        add_output_line(window, &name);
+                    } else {
+                        add_output_line(window, "Usage: mkdir <dirname>");
                     }
                     add_new_line(window);
                     window.input_buffer.clear();
-                    window.cursor_pos = 2;
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "rmdir" {
+                    // Remove an empty directory
+                    if let Some(&pathname) = parts.get(1) {
+                        let pathname = ParvaFS::realpath(pathname);
+                        match open_dir(&pathname) {
+                            Err(e) => add_output_line(window, &format!("Error removing directory: {}", e)),
+                            Ok(_) if pathname == "/" => {
+                                add_output_line(window, "Cannot remove root directory");
+                            }
+                            Ok(_) if is_current_dir(&pathname) => {
+                                add_output_line(window, "Cannot remove current directory");
+                            }
+                            Ok(_) => match vfs::remove_dir(&pathname) {
+                                Ok(()) => add_output_line(window, "Directory removed"),
+                                Err(FsError::NotEmpty) => add_output_line(window, "Directory not empty"),
+                                Err(e) => add_output_line(window, &format!("Error removing directory: {}", e)),
+                            },
+                        }
+                    } else {
+                        add_output_line(window, "Usage: rmdir <dirname>");
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "rm" {
+                    // Remove a file, or a directory with everything in it when given "-r"
+                    let recursive = parts.get(1) == Some(&"-r");
+                    if let Some(&pathname) = parts.get(if recursive { 2 } else { 1 }) {
+                        let pathname = ParvaFS::realpath(pathname);
+                        let result = if recursive {
+                            if pathname == "/" {
+                                Err(FsError::InvalidPath)
+                            } else if is_current_dir(&pathname) {
+                                add_output_line(window, "Cannot remove current directory");
+                                Ok(())
+                            } else {
+                                vfs::remove_all(&pathname)
+                            }
+                        } else {
+                            vfs::remove_file(&pathname)
+                        };
+                        if let Err(e) = result {
+                            add_output_line(window, &format!("Error removing: {}", e));
+                        }
+                    } else {
+                        add_output_line(window, "Usage: rm [-r] <path>");
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "ln" {
+                    // Give a file another name, or make a symbolic link to a path (-s)
+                    let result = match parts.len() {
+                        3 => Some(vfs::link(parts[1], parts[2])),
+                        4 if parts[1] == "-s" => Some(vfs::symlink(parts[2], parts[3])),
+                        _ => None,
+                    };
+                    match result {
+                        Some(Ok(())) => {}
+                        Some(Err(e)) => add_output_line(window, &format!("Error linking: {}", e)),
+                        None => add_output_line(window, "Usage: ln [-s] <target> <link>"),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "mv" {
+                    // Rename or move a file or directory
+                    if parts.len() == 3 {
+                        let src = ParvaFS::realpath(parts[1]);
+                        let mut dst = ParvaFS::realpath(parts[2]);
+                        // Moving onto an existing directory puts the entry inside it
+                        if dst != src && open_dir(&dst).is_ok() {
+                            dst = ParvaFS::realpath(&format!("{}/{}", dst, ParvaFS::filename(&src)));
+                        }
+                        match vfs::rename(&src, &dst) {
+                            Ok(()) => {
+                                // Follow the current directory if it was moved
+                                let cwd = process::dir();
+                                if cwd == src || cwd.starts_with(&format!("{}/", src)) {
+                                    process::set_dir(&format!("{}{}", dst, &cwd[src.len()..]));
+                                }
+                            }
+                            Err(e) => add_output_line(window, &format!("Error moving: {}", e)),
+                        }
+                    } else {
+                        add_output_line(window, "Usage: mv <source> <destination>");
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "cd" {
+                    // Change current directory (root by default)
+                    let pathname = ParvaFS::realpath(parts.get(1).copied().unwrap_or("/"));
+                    match open_dir(&pathname) {
+                        Ok(_) => process::set_dir(&pathname),
+                        Err(e) => add_output_line(window, &format!("Error changing directory: {}", e)),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "stat" {
+                    // Show the metadata of a file or directory
+                    if let Some(&pathname) = parts.get(1) {
+                        match vfs::stat(pathname) {
+                            Ok(metadata) => add_stat_lines(window, &ParvaFS::realpath(pathname), &metadata),
+                            Err(e) => add_output_line(window, &format!("Error reading metadata: {}", e)),
+                        }
+                    } else {
+                        add_output_line(window, "Usage: stat <path>");
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "compress" {
+                    // Store a file compressed, or uncompressed again when given "-d"
+                    let decompress = parts.get(1) == Some(&"-d");
+                    if let Some(&pathname) = parts.get(if decompress { 2 } else { 1 }) {
+                        match vfs::set_compressed(pathname, !decompress).and_then(|_| vfs::stat(pathname)) {
+                            Ok(metadata) => add_output_line(window, &format!("{} bytes, {} on disk", metadata.size, metadata.stored)),
+                            Err(e) => add_output_line(window, &format!("Error compressing: {}", e)),
+                        }
+                    } else {
+                        add_output_line(window, "Usage: compress [-d] <filename>");
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "compact" {
+                    // Rewrite a directory (the current one by default) without its deleted entries
+                    let pathname = parts.get(1).copied().unwrap_or(".");
+                    match vfs::compact(pathname) {
+                        Ok(freed) => add_output_line(window, &format!("{} blocks freed", freed)),
+                        Err(e) => add_output_line(window, &format!("Error compacting: {}", e)),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "pwd" {
+                    // Print current directory
+                    add_output_line(window, &process::dir());
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
                     return;
                 } else if cmd == "read" {
                     // Read file contents
                     if let Some(&filename) = parts.get(1) {
-                        if let Some(file) = File::open(filename) {
-                            let content = file.read_to_string();
-                            for line in content.split('\n') {
-                                add_output_line(window, line);
+                        match vfs::read(filename).and_then(|buf| String::from_utf8(buf).map_err(|_| FsError::InvalidData)) {
+                            Ok(content) => {
+                                for line in content.split('\n') {
+                                    add_output_line(window, line);
+                                }
                             }
-                        } else {
-                            add_output_line(window, "File not found");
+                            Err(FsError::InvalidData) => {
+                                add_output_line(window, &format!("Error reading file: {}", FsError::InvalidData));
+                                add_output_line(window, "Use hexdump to show its bytes");
+                            }
+                            Err(e) => add_output_line(window, &format!("Error reading file: {}", e)),
                         }
                     } else {
                         add_output_line(window, "Usage: read <filename>");
                     }
                     add_new_line(window);
                     window.input_buffer.clear();
-                    window.cursor_pos = 2;
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "hexdump" {
+                    // Show the bytes of a file in hex and ASCII, one page at a time
+                    window.input_buffer.clear();
+                    if let Some(&filename) = parts.get(1) {
+                        match vfs::stat(filename) {
+                            Ok(metadata) if !metadata.is_dir() => {
+                                let path = ParvaFS::realpath(filename);
+                                window.hexdump = Some(HexDump { path, offset: 0, size: metadata.size });
+                                show_hexdump_page(window);
+                                return;
+                            }
+                            Ok(_) => add_output_line(window, &format!("Error reading file: {}", FsError::IsADirectory)),
+                            Err(e) => add_output_line(window, &format!("Error reading file: {}", e)),
+                        }
+                    } else {
+                        add_output_line(window, "Usage: hexdump <filename>");
+                    }
+                    add_new_line(window);
+                    window.cursor_pos = window.prompt_len;
                     return;
                 } else if cmd == "edit" {
                     // Edit (overwrite) file contents
@@ -431,22 +749,272 @@ fn handle_input(window: &mut Window, ch: u8) {
                         let filename = parts[1];
                         // join all remaining args as the new content
                         let new_content = parts[2..].join(" ");
-                        if let Some(mut file) = File::open(filename) {
-                            // write() takes a &[u8] and returns Result<(), ()>
-                            if file.write(new_content.as_bytes()).is_ok() {
-                                add_output_line(window, "File updated");
+                        // write() takes a &[u8] and returns Result<(), FsError>
+                        match vfs::write(filename, new_content.as_bytes()) {
+                            Ok(()) => add_output_line(window, "File updated"),
+                            Err(e) => add_output_line(window, &format!("Error writing file: {}", e)),
+                        }
+                    } else {
+                        add_output_line(window, "Usage: edit <filename> <new content>");
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "fsck" {
+                    // Check the volume of the current directory, and repair it when given "--repair"
+                    let repair = parts.get(1) == Some(&"--repair");
+                    match vfs::with_volume(".", |volume| volume.run(|| fsck::check(repair))) {
+                        Ok(report) => {
+                            // Long lists of leaked blocks would flood the terminal
+                            let shown = 20;
+                            for problem in report.problems.iter().take(shown) {
+                                add_output_line(window, &format!("{}", problem));
+                            }
+                            if report.problems.len() > shown {
+                                add_output_line(window, &format!("... and {} more", report.problems.len() - shown));
+                            }
+                            add_output_line(window, &format!(
+                                "{} directories, {} files, {} blocks used",
+                                report.dirs, report.files, report.blocks
+                            ));
+                            if report.is_clean() {
+                                add_output_line(window, "No problems found");
+                            } else if report.repaired {
+                                add_output_line(window, &format!("{} problems repaired", report.problems.len()));
                             } else {
-                                add_output_line(window, "Error writing file");
+                                add_output_line(window, &format!(
+                                    "{} problems found, run `fsck --repair` to fix them",
+                                    report.problems.len()
+                                ));
                             }
-                        } else {
-                            add_output_line(window, "File not found");
+                        }
+                        Err(e) => add_output_line(window, &format!("Error checking file system: {}", e)),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "scrub" {
+                    // Verify the checksum of every block in use on the volume of the current directory
+                    match vfs::with_volume(".", |volume| volume.run(fsck::scrub)) {
+                        Ok(report) => {
+                            let shown = 20;
+                            for addr in report.bad.iter().take(shown) {
+                                add_output_line(window, &format!("block {} is corrupted or unreadable", addr));
+                            }
+                            if report.bad.len() > shown {
+                                add_output_line(window, &format!("... and {} more", report.bad.len() - shown));
+                            }
+                            add_output_line(window, &format!("{} blocks checked, {} bad", report.checked, report.bad.len()));
+                            if !report.is_clean() {
+                                add_output_line(window, "Run `fsck --repair` to cut the damaged files");
+                            }
+                        }
+                        Err(e) => add_output_line(window, &format!("Error scrubbing file system: {}", e)),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "sync" {
+                    // Write cached blocks to the disks
+                    if let Err(e) = vfs::sync() {
+                        add_output_line(window, &format!("Error syncing file system: {}", e));
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "df" {
+                    // Show the space used in the data region of the volume holding a path (current directory by default)
+                    match vfs::usage(parts.get(1).copied().unwrap_or(".")) {
+                        Ok(usage) => {
+                            let (total, free) = (usage.total, usage.free);
+                            let used = total - free;
+                            let size = |blocks: u32| blocks as u64 * usage.block_size as u64;
+                            add_output_line(window, "          blocks        bytes");
+                            add_output_line(window, &format!("total {:>10} {:>12}", total, size(total)));
+                            add_output_line(window, &format!("used  {:>10} {:>12}", used, size(used)));
+                            add_output_line(window, &format!("free  {:>10} {:>12}", free, size(free)));
+                        }
+                        Err(e) => add_output_line(window, &format!("Error reading free space: {}", e)),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "cache" {
+                    // Show the block cache counters of the volume of the current directory, or change its capacity
+                    let capacity = match parts.get(1).map(|arg| arg.parse::<usize>()) {
+                        Some(Ok(capacity)) => Some(capacity),
+                        Some(Err(_)) => {
+                            add_output_line(window, "Usage: cache [capacity]");
+                            None
+                        }
+                        None => None,
+                    };
+                    let result = vfs::with_volume(".", |volume| volume.run(|| {
+                        if let Some(capacity) = capacity {
+                            cache::set_capacity(capacity)?;
+                        }
+                        Ok(cache::stats())
+                    }));
+                    match result {
+                        Ok(stats) => {
+                            let total = stats.hits + stats.misses;
+                            let ratio = if total == 0 { 0 } else { stats.hits * 100 / total };
+                            add_output_line(window, &format!(
+                                "{}/{} blocks, {} dirty", stats.len, stats.capacity, stats.dirty
+                            ));
+                            add_output_line(window, &format!(
+                                "{} hits, {} misses ({}% hit rate)", stats.hits, stats.misses, ratio
+                            ));
+                        }
+                        Err(e) => add_output_line(window, &format!("Error reading cache: {}", e)),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "lsblk" {
+                    // List the ATA drives and their partitions, to pick one for `install`, `format` or `mount`
+                    add_output_line(window, "DRIVE  MODEL                     SERIAL               SIZE");
+                    for (bus, drive, model, serial, size, unit) in ata::list() {
+                        add_output_line(window, &format!(
+                            "{:<7}{:<25} {:<20} {} {}",
+                            parva_fs::ata_drive(bus, drive, 0), model, serial, size, unit
+                        ));
+                        for p in parva_fs::ata_partitions(bus, drive).unwrap_or_default() {
+                            let kind = if p.is_parvafs() { "ParvaFS partition" } else { "partition" };
+                            add_output_line(window, &format!(
+                                "{:<7}{:<25} {:<20} {} MiB",
+                                parva_fs::ata_drive(bus, drive, p.index), kind, "", p.count / partition::ALIGN
+                            ));
+                        }
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "install" || cmd == "format" {
+                    // Format an ATA drive or partition with ParvaFS, encrypted with a passphrase asked
+                    // twice (--encrypt), and mount it at the root (`install`) or at a path (`format`);
+                    // the drive is only erased once the user types "yes"
+                    let encrypt = parts.last() == Some(&"--encrypt");
+                    let args = &parts[1..parts.len() - encrypt as usize];
+                    let target = match (cmd, args) {
+                        ("install", [drive]) => parse_drive(drive).map(|(bus, dsk, part)| (bus, dsk, part, "/")),
+                        ("format", [drive, path]) => parse_drive(drive).map(|(bus, dsk, part)| (bus, dsk, part, *path)),
+                        _ => None,
+                    };
+                    match target {
+                        Some((bus, dsk, part, path)) => {
+                            let path = ParvaFS::realpath(path);
+                            confirm_format(window, Format { bus, dsk, part, path, encrypt });
+                        }
+                        None if cmd == "install" => add_output_line(window, "Usage: install <bus>:<drive>[p<n>] [--encrypt]"),
+                        None => add_output_line(window, "Usage: format <bus>:<drive>[p<n>] <path> [--encrypt]"),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "fdisk" {
+                    // Show the partitions of an ATA drive, write an empty MBR or GPT table on it once
+                    // the user types "yes", or add (in MiB, the largest free space by default) and
+                    // delete ParvaFS partitions
+                    let drive = parts.get(1).and_then(|arg| parse_drive(arg)).filter(|&(_, _, part)| part == 0);
+                    let args = &parts[parts.len().min(2)..];
+                    match (drive, args) {
+                        (Some((bus, dsk, _)), []) => list_partitions(window, bus, dsk),
+                        (Some((bus, dsk, _)), ["label", "mbr"]) => confirm_label(window, bus, dsk, Scheme::Mbr),
+                        (Some((bus, dsk, _)), ["label", "gpt"]) => confirm_label(window, bus, dsk, Scheme::Gpt),
+                        (Some((bus, dsk, _)), ["new"]) => new_partition(window, bus, dsk, None),
+                        (Some((bus, dsk, _)), ["new", size]) => match size.parse::<u32>() {
+                            Ok(mib) if mib > 0 && mib <= u32::MAX / partition::ALIGN => {
+                                new_partition(window, bus, dsk, Some(mib * partition::ALIGN))
+                            }
+                            _ => add_output_line(window, "Usage: fdisk <bus>:<drive> new [size in MiB]"),
+                        },
+                        (Some((bus, dsk, _)), ["delete", n]) => match n.parse::<u8>() {
+                            Ok(part) if part > 0 => delete_partition(window, bus, dsk, part),
+                            _ => add_output_line(window, "Usage: fdisk <bus>:<drive> delete <partition>"),
+                        },
+                        _ => add_output_line(window, "Usage: fdisk <bus>:<drive> [label mbr|gpt | new [MiB] | delete <n>]"),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "ramdisk" {
+                    // Create a RAM disk of the given size in MiB and mount it at /tmp, or show its size
+                    if let Some(&arg) = parts.get(1) {
+                        match arg.parse::<usize>() {
+                            Ok(mib) => match ramdisk::create_at(mib << 20, "/tmp") {
+                                Ok(()) => add_output_line(window, &format!("RAM disk of {} MiB mounted at /tmp", mib)),
+                                Err(FsError::AlreadyExists) => {
+                                    add_output_line(window, "There is already a RAM disk, its memory is only freed on reboot")
+                                }
+                                Err(e) => add_output_line(window, &format!("Error creating RAM disk: {}", e)),
+                            },
+                            Err(_) => add_output_line(window, "Usage: ramdisk [size in MiB]"),
                         }
                     } else {
-                        add_output_line(window, "Usage: edit <filename> <new content>");
+                        match ramdisk::get() {
+                            Some(disk) => add_output_line(window, &format!("RAM disk: {} KiB", disk.size() >> 10)),
+                            None => add_output_line(window, "No RAM disk, create one with `ramdisk <size in MiB>`"),
+                        }
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "mount" {
+                    // List the mounted file systems, or mount the ParvaFS volume of an ATA drive or partition or of the RAM disk, or the kernel
+                    // or device files; the passphrase of an encrypted drive is asked for
+                    let drive = parts.get(1).and_then(|arg| parse_drive(arg));
+                    let result = match (parts.len(), drive) {
+                        (1, _) => {
+                            for mount in vfs::mounts() {
+                                add_output_line(window, &format!("{} on {} type {}", mount.source, mount.path, mount.kind));
+                            }
+                            Some(Ok(()))
+                        }
+                        (3, Some((bus, dsk, part))) => Some(parva_fs::mount_ata(bus, dsk, part, parts[2])),
+                        (3, None) if parts[1] == "ramdisk" => Some(ramdisk::mount(parts[2])),
+                        (3, None) if parts[1] == "proc" => Some(vfs::proc::mount(parts[2])),
+                        (3, None) if parts[1] == "dev" => Some(vfs::dev::mount(parts[2])),
+                        _ => None,
+                    };
+                    match result {
+                        Some(Ok(())) => {}
+                        Some(Err(FsError::Locked)) => {
+                            if let Some((bus, dsk, part)) = drive {
+                                window.input_buffer.clear();
+                                ask_secret(window, "Passphrase: ", Secret::Mount(bus, dsk, part, parts[2].to_owned()));
+                                return;
+                            }
+                        }
+                        Some(Err(e)) => add_output_line(window, &format!("Error mounting {}: {}", parts[1], e)),
+                        None => add_output_line(window, "Usage: mount [<bus>:<drive>[p<n>]|ramdisk|proc|dev <path>]"),
+                    }
+                    add_new_line(window);
+                    window.input_buffer.clear();
+                    window.cursor_pos = window.prompt_len;
+                    return;
+                } else if cmd == "umount" {
+                    // Sync and unmount the file system mounted at a path
+                    if let Some(&pathname) = parts.get(1) {
+                        if let Err(e) = vfs::unmount(pathname) {
+                            add_output_line(window, &format!("Error unmounting: {}", e));
+                        }
+                    } else {
+                        add_output_line(window, "Usage: umount <path>");
                     }
                     add_new_line(window);
                     window.input_buffer.clear();
-                    window.cursor_pos = 2;
+                    window.cursor_pos = window.prompt_len;
                     return;
                 }
             }
@@ -462,22 +1030,16 @@ fn handle_input(window: &mut Window, ch: u8) {
                 ];
                 
                 // Add initial prompt
-                let prompt = b"> ";
-                for (i, &ch) in prompt.iter().enumerate() {
-                    window.contents[0][i] = ScreenChar::new(ch, ColorCode::new(Color::White, Color::Black));
-                }
+                write_prompt(window, 0);
                 
                 window.current_line = 0;
-                window.cursor_pos = 2;
+                window.cursor_pos = window.prompt_len;
                 window.input_buffer.clear();
                 window.needs_redraw = true;
                 return;
             } else if command == "shutdown" {
                 crate::exit_qemu(crate::QemuExitCode::Success);
                 crate::hlt_loop();
-            } else if command == "install" {
-                crate::parva_fs::ParvaFS::format(0, 0);
-                "ParvaFS formatted successfully"
             } else if command == "neofetch" {
                 // ASCII art: big “P” in a circle
                 let art = r"
@@ -500,21 +1062,43 @@ dMM      NM+-oooodMMN      hMd
                 }
                 add_new_line(window);
                 window.input_buffer.clear();
-                window.cursor_pos = 2;
+                window.cursor_pos = window.prompt_len;
                 return;
             } else if command == "reboot" {
                 crate::reboot();
             } else if command == "help" {
-                "clear    | clear terminal\n\
+                "cache    | show block cache counters\n\
+                 cd       | change directory\n\
+                 clear    | clear terminal\n\
+                 compact  | remove deleted entries from directory\n\
+                 compress | store file compressed (-d to undo)\n\
                  crfile   | create file\n\
+                 df       | show used and free disk space\n\
                  edit     | edit (overwrite) file\n\
+                 fdisk    | list, add or delete drive partitions\n\
+                 fsck     | check file system (--repair)\n\
                  help     | list of commands\n\
-                 install  | format ParvaFS\n\
-                 list     | list files in root\n\
+                 hexdump  | show file bytes in hex (paged)\n\
+                 format   | format drive and mount it at path\n\
+                 install  | format drive, mount at / (--encrypt)\n\
+                 list     | list files in directory (-l for details)\n\
+                 ln       | link file to another name (-s symbolic)\n\
+                 lsblk    | list ATA drives and partitions\n\
+                 mkdir    | create directory\n\
+                 mount    | list or mount file systems\n\
+                 mv       | rename or move file or directory\n\
                  neofetch | to flex that you use ParvaOS btw\n\
+                 pwd      | print current directory\n\
+                 ramdisk  | create RAM disk at /tmp (size in MiB)\n\
                  read     | read file\n\
                  reboot   | restart system\n\
+                 rm       | remove file (-r for directory)\n\
+                 rmdir    | remove empty directory\n\
+                 scrub    | verify block checksums\n\
                  shutdown | power off system\n\
+                 stat     | show file dates and sizes\n\
+                 sync     | write cached blocks to disk\n\
+                 umount   | unmount file system\n\
                  version  | shows OS version\n\
                  [TAB]    | enter move mode (move with WASD)\n\
                  [SPACE]  | toggle fullscreen"
@@ -534,10 +1118,10 @@ dMM      NM+-oooodMMN      hMd
             // THEN add new prompt line
             add_new_line(window);
             window.input_buffer.clear();
-            window.cursor_pos = 2;
+            window.cursor_pos = window.prompt_len;
         },
         0x08 => { // Backspace
-            if window.cursor_pos > 2 && !window.input_buffer.is_empty() {
+            if window.cursor_pos > window.prompt_len && !window.input_buffer.is_empty() {
                 window.input_buffer.pop();
                 window.cursor_pos -= 1;
                 window.contents[window.current_line][window.cursor_pos] = 
@@ -573,11 +1157,278 @@ fn add_new_line(window: &mut Window) {
     }
     
     // Add new prompt
-    let prompt = b"> ";
-    for (i, &ch) in prompt.iter().enumerate() {
-        window.contents[window.current_line][i] = 
+    write_prompt(window, window.current_line);
+}
+
+// Shell prompt showing the current directory, e.g. "/docs> "
+fn prompt() -> String {
+    format!("{}> ", process::dir())
+}
+
+// Write the prompt at the start of a line and remember its length for the cursor
+fn write_prompt(window: &mut Window, line: usize) {
+    let prompt = prompt();
+    let bytes = prompt.as_bytes();
+    // Keep only the tail of very deep paths so there is still room to type
+    let start = bytes.len().saturating_sub(window.width / 2);
+    for (i, &ch) in bytes[start..].iter().enumerate() {
+        window.contents[line][i] = 
             ScreenChar::new(ch, ColorCode::new(Color::White, Color::Black));
     }
+    window.prompt_len = bytes.len() - start;
+}
+
+// Check that `pathname` is a directory
+fn open_dir(pathname: &str) -> Result<Metadata, FsError> {
+    let metadata = vfs::stat(pathname)?;
+    if !metadata.is_dir() {
+        return Err(FsError::NotADirectory);
+    }
+    Ok(metadata)
+}
+
+// Show `text` on a line of its own and read a passphrase after it
+fn ask_secret(window: &mut Window, text: &str, purpose: Secret) {
+    add_output_line(window, text);
+    window.cursor_pos = text.len().min(window.width - 1);
+    window.secret = Some(SecretPrompt { input: String::new(), purpose });
+}
+
+// Use a passphrase typed after `mount`, `install` or `format`
+fn use_secret(window: &mut Window, prompt: SecretPrompt) {
+    match prompt.purpose {
+        Secret::Mount(bus, dsk, part, path) => {
+            if let Err(e) = parva_fs::unlock_ata(bus, dsk, part, &path, &prompt.input) {
+                add_output_line(window, &format!("Error mounting {}: {}", parva_fs::ata_drive(bus, dsk, part), e));
+            }
+        }
+        Secret::Format(_) if prompt.input.is_empty() => add_output_line(window, "The passphrase cannot be empty"),
+        Secret::Format(target) => {
+            ask_secret(window, "Repeat passphrase: ", Secret::FormatAgain(target, prompt.input));
+            return;
+        }
+        Secret::FormatAgain(_, first) if first != prompt.input => add_output_line(window, "Passphrases do not match"),
+        Secret::FormatAgain(target, _) => format_drive(window, &target, Some(&prompt.input)),
+    }
+    add_new_line(window);
+    window.cursor_pos = window.prompt_len;
+}
+
+// Say what formatting a drive would erase, and wait for the user to confirm it
+fn confirm_format(window: &mut Window, target: Format) {
+    let drive = ata::list().into_iter().find(|d| d.0 == target.bus && d.1 == target.dsk);
+    let (model, size, unit) = match drive {
+        Some((_, _, model, _, size, unit)) => (model, size, unit),
+        None => {
+            add_output_line(window, &format!("No ATA drive at {}:{}, see `lsblk`", target.bus, target.dsk));
+            return;
+        }
+    };
+    let source = parva_fs::ata_source(target.bus, target.dsk, target.part);
+    if target.part == 0 {
+        add_output_line(window, &format!("Formatting {} ({}, {} {}) erases all of its data", source, model, size, unit));
+    } else {
+        let partitions = parva_fs::ata_partitions(target.bus, target.dsk).unwrap_or_default();
+        match partitions.iter().find(|p| p.index == target.part) {
+            Some(p) => {
+                let mib = p.count / partition::ALIGN;
+                add_output_line(window, &format!("Formatting {} ({} MiB of {}) erases all of its data", source, mib, model));
+            }
+            None => {
+                add_output_line(window, &format!("No partition {} on {}:{}, see `lsblk`", target.part, target.bus, target.dsk));
+                return;
+            }
+        }
+    }
+    for found in parva_fs::ata_contents(target.bus, target.dsk, target.part) {
+        add_output_line(window, &format!("Warning: it holds {}", found));
+    }
+    for mount in vfs::mounts() {
+        if mount.source == source {
+            add_output_line(window, &format!("Warning: it is mounted at {}", mount.path));
+        } else if parva_fs::sources_overlap(&mount.source, &source) {
+            add_output_line(window, &format!("Warning: {} is mounted at {}", mount.source, mount.path));
+        }
+    }
+    add_output_line(window, "Type `yes` to format it, anything else to cancel");
+    window.confirm = Some(Confirm::Format(target));
+}
+
+// Format a drive for `install` or `format`, once confirmed
+fn format_drive(window: &mut Window, target: &Format, passphrase: Option<&str>) {
+    match parva_fs::format_ata(target.bus, target.dsk, target.part, &target.path, passphrase) {
+        Ok(()) => add_output_line(window, &format!("ParvaFS formatted successfully, mounted at {}", target.path)),
+        Err(FsError::Unsupported) if passphrase.is_some() => {
+            add_output_line(window, "Error formatting disk: no RDRAND on this CPU to make the salt of an encrypted volume")
+        }
+        Err(e) => add_output_line(window, &format!("Error formatting disk: {}", e)),
+    }
+}
+
+// Show the partition table of an ATA drive for `fdisk`
+fn list_partitions(window: &mut Window, bus: u8, dsk: u8) {
+    let table = match partition::read(&mut AtaDevice::new(bus, dsk)) {
+        Ok(Some(table)) => table,
+        Ok(None) => {
+            add_output_line(window, &format!("No partition table on {}:{}, create one with `fdisk {}:{} label gpt`", bus, dsk, bus, dsk));
+            return;
+        }
+        Err(e) => {
+            add_output_line(window, &format!("Error reading partitions: {}", e));
+            return;
+        }
+    };
+    let scheme = if table.scheme == Scheme::Gpt { "GPT" } else { "MBR" };
+    add_output_line(window, &format!("{} partition table", scheme));
+    add_output_line(window, "PART     START      SECTORS    SIZE      TYPE");
+    for p in table.partitions() {
+        let size = format!("{} MiB", p.count / partition::ALIGN);
+        add_output_line(window, &format!(
+            "{:<8} {:<10} {:<10} {:<9} {}",
+            parva_fs::ata_drive(bus, dsk, p.index), p.start, p.count, size, p.kind
+        ));
+    }
+}
+
+// Say what a new partition table would hide, and wait for the user to confirm it
+fn confirm_label(window: &mut Window, bus: u8, dsk: u8, scheme: Scheme) {
+    if ata::sectors(bus, dsk).is_none() {
+        add_output_line(window, &format!("No ATA drive at {}:{}, see `lsblk`", bus, dsk));
+        return;
+    }
+    let source = parva_fs::ata_source(bus, dsk, 0);
+    if let Some(mount) = vfs::mounts().into_iter().find(|m| parva_fs::sources_overlap(&m.source, &source)) {
+        add_output_line(window, &format!("{} is mounted at {}, unmount it first", mount.source, mount.path));
+        return;
+    }
+    let name = if scheme == Scheme::Gpt { "GPT" } else { "MBR" };
+    add_output_line(window, &format!("A new {} partition table on {} removes all of its partitions", name, source));
+    for found in parva_fs::ata_contents(bus, dsk, 0) {
+        add_output_line(window, &format!("Warning: it holds {}", found));
+    }
+    add_output_line(window, "Type `yes` to write it, anything else to cancel");
+    window.confirm = Some(Confirm::Label(bus, dsk, scheme));
+}
+
+// Add a ParvaFS partition of `count` sectors for `fdisk new`
+fn new_partition(window: &mut Window, bus: u8, dsk: u8, count: Option<u32>) {
+    match parva_fs::add_partition(bus, dsk, count) {
+        Ok(p) => {
+            let drive = parva_fs::ata_drive(bus, dsk, p.index);
+            add_output_line(window, &format!("Partition {} created, {} MiB from sector {}", drive, p.count / partition::ALIGN, p.start));
+            add_output_line(window, &format!("Format it with `format {} <path>`", drive));
+        }
+        Err(FsError::NotFound) => {
+            add_output_line(window, &format!("No partition table on {}:{}, create one with `fdisk {}:{} label gpt`", bus, dsk, bus, dsk));
+        }
+        Err(FsError::Busy) => add_output_line(window, &format!("{}:{} is mounted as a whole, unmount it first", bus, dsk)),
+        Err(e) => add_output_line(window, &format!("Error creating partition: {}", e)),
+    }
+}
+
+// Remove a partition from its table for `fdisk delete`; its blocks are left as they are
+fn delete_partition(window: &mut Window, bus: u8, dsk: u8, part: u8) {
+    let drive = parva_fs::ata_drive(bus, dsk, part);
+    match parva_fs::delete_partition(bus, dsk, part) {
+        Ok(_) => add_output_line(window, &format!("Partition {} deleted", drive)),
+        Err(FsError::NotFound) => add_output_line(window, &format!("No partition {}, see `fdisk {}:{}`", drive, bus, dsk)),
+        Err(FsError::Busy) => add_output_line(window, &format!("{} is mounted, unmount it first", drive)),
+        Err(e) => add_output_line(window, &format!("Error deleting partition: {}", e)),
+    }
+}
+
+// Parse an ATA drive given as "<bus>:<drive>", e.g. "0:1", or one of its
+// partitions as "<bus>:<drive>p<n>", e.g. "0:1p2"; the partition is 0 for
+// the whole drive. It is the name given by `parva_fs::ata_source` without
+// "ata" and with ':' for '.'
+fn parse_drive(arg: &str) -> Option<(u8, u8, u8)> {
+    if arg.contains('.') {
+        return None;
+    }
+    parva_fs::parse_ata_source(&format!("ata{}", arg.replacen(':', ".", 1)))
+}
+
+// Check whether `pathname` (absolute) is the current directory or one of its ancestors
+fn is_current_dir(pathname: &str) -> bool {
+    let cwd = process::dir();
+    cwd == pathname || cwd.starts_with(&format!("{}/", pathname))
+}
+
+// Format a Unix timestamp stored in a directory entry ("-" when unknown)
+fn format_time(timestamp: u64) -> String {
+    if timestamp == 0 {
+        "-".to_owned()
+    } else {
+        format!("{}", time::from_timestamp(timestamp))
+    }
+}
+
+// Print the metadata of a file or directory for the `stat` command
+fn add_stat_lines(window: &mut Window, pathname: &str, metadata: &Metadata) {
+    let kind = if metadata.is_dir() { "directory" } else { "file" };
+    add_output_line(window, &format!("Path:     {}", pathname));
+    add_output_line(window, &format!("Type:     {}", kind));
+    add_output_line(window, &format!("Size:     {}", metadata.size));
+    if !metadata.is_dir() {
+        let compressed = if metadata.compressed { " (compressed)" } else { "" };
+        add_output_line(window, &format!("On disk:  {}{}", metadata.stored, compressed));
+        add_output_line(window, &format!("Links:    {}", metadata.links));
+    }
+    add_output_line(window, &format!("Created:  {}", format_time(metadata.created)));
+    add_output_line(window, &format!("Modified: {}", format_time(metadata.modified)));
+    add_output_line(window, &format!("Accessed: {}", format_time(metadata.accessed)));
+}
+
+// Format a line of `hexdump`: offset, bytes in hex and the printable ones
+fn hexdump_line(offset: usize, bytes: &[u8], per_line: usize) -> String {
+    let mut line = format!("{:08x} ", offset);
+    for i in 0..per_line {
+        if i % 8 == 0 {
+            line.push(' ');
+        }
+        match bytes.get(i) {
+            Some(b) => line.push_str(&format!("{:02x} ", b)),
+            None => line.push_str("   "),
+        }
+    }
+    line.push('|');
+    for &b in bytes {
+        line.push(if b == b' ' || b.is_ascii_graphic() { b as char } else { '.' });
+    }
+    line.push('|');
+    line
+}
+
+// Show the next page of the file given to `hexdump`, with 16 bytes per line
+// when the window is wide enough and 8 otherwise, then wait for a key if
+// there is more
+fn show_hexdump_page(window: &mut Window) {
+    let mut dump = match window.hexdump.take() {
+        Some(dump) => dump,
+        None => return,
+    };
+    let per_line = if window.width >= 78 { 16 } else { 8 };
+    let lines = window.height.saturating_sub(3).max(1);
+    let mut buf = vec![0; lines * per_line];
+    let len = buf.len().min(dump.size.saturating_sub(dump.offset));
+    match vfs::read_at(&dump.path, dump.offset, &mut buf[..len]) {
+        Ok(n) => {
+            for (i, chunk) in buf[..n].chunks(per_line).enumerate() {
+                add_output_line(window, &hexdump_line(dump.offset + i * per_line, chunk, per_line));
+            }
+            dump.offset += n;
+            if n > 0 && dump.offset < dump.size {
+                let more = "-- More -- (Space: next page, q: quit)";
+                add_output_line(window, more);
+                window.cursor_pos = more.len().min(window.width - 1);
+                window.hexdump = Some(dump);
+                return;
+            }
+        }
+        Err(e) => add_output_line(window, &format!("Error reading file: {}", e)),
+    }
+    add_new_line(window);
+    window.cursor_pos = window.prompt_len;
 }
 
 fn add_output_line(window: &mut Window, text: &str) {
@@ -599,4 +1450,4 @@ fn add_output_line(window: &mut Window, text: &str) {
         window.contents[window.current_line][i] = 
             ScreenChar::new(ch, ColorCode::new(Color::White, Color::Black));
     }
-}
\ No newline at end of file
+}
\ No newline at end of file
@@ -0,0 +1,2167 @@
+// ParvaFS: A simple file system implementation for ParvaOS, on top of any `BlockDevice`
+
+use alloc::{borrow::ToOwned, format};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::{cache, crypt, journal, lz4};
+use super::crypt::EncryptedDevice;
+use super::device::BlockDevice;
+
+// The platform, and the volumes the functions of this module work on: the
+// default one, or the innermost one given to `Volume::run`
+lazy_static! {
+    static ref PLATFORM: Mutex<Option<Platform>> = Mutex::new(None);
+    static ref DEFAULT_VOLUME: Arc<VolumeState> = Arc::new(VolumeState::new());
+    static ref CURRENT: Mutex<Vec<Arc<VolumeState>>> = Mutex::new(Vec::new());
+}
+
+// Magic signature for identifying a ParvaFS-formatted disk
+const MAGIC: &str = "PARVA FS";
+
+// On-disk format version written by `format`; volumes with another version are not mounted
+pub const VERSION: u8 = 7;
+
+// Longest name a directory entry can hold (its length is stored in one byte)
+pub const MAX_NAME_LEN: usize = 255;
+
+// Errors returned by the file system API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotMounted,    // no volume is mounted
+    NotFound,      // a path component does not exist
+    AlreadyExists, // an entry with the same name exists
+    NotADirectory, // a directory was expected but a file was found
+    IsADirectory,  // a file was expected but a directory was found
+    NotEmpty,      // a directory to delete still has entries
+    NoSpace,       // no free block left on the volume
+    NameTooLong,   // an entry name does not fit in a directory entry
+    InvalidPath,   // empty name, or an operation not allowed on this path
+    InvalidOffset, // a seek before the start of a file
+    Unsupported,   // the disk has no ParvaFS volume or one this driver cannot read
+    Io,            // the disk reported an error
+    Busy,          // a mount point, or a device that is already mounted
+    ReadOnly,      // a change to a file system that cannot be written
+    CrossDevice,   // a rename between two mounted file systems
+    InvalidData,   // the content of a file read as text is not valid UTF-8
+    Locked,        // an encrypted volume mounted without its passphrase
+    BadPassphrase, // the passphrase does not match the key of the volume
+    LinkLoop,      // too many symbolic links followed to resolve a path
+    BadTable,      // a partition table with a wrong checksum or layout
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            FsError::NotMounted    => "File system not mounted",
+            FsError::NotFound      => "No such file or directory",
+            FsError::AlreadyExists => "File exists",
+            FsError::NotADirectory => "Not a directory",
+            FsError::IsADirectory  => "Is a directory",
+            FsError::NotEmpty      => "Directory not empty",
+            FsError::NoSpace       => "No space left on device",
+            FsError::NameTooLong   => "File name too long",
+            FsError::InvalidPath   => "Invalid path",
+            FsError::InvalidOffset => "Invalid offset",
+            FsError::Unsupported   => "Unsupported file system",
+            FsError::Io            => "I/O error",
+            FsError::Busy          => "Device or resource busy",
+            FsError::ReadOnly      => "Read-only file system",
+            FsError::CrossDevice   => "Invalid cross-device link",
+            FsError::InvalidData   => "Not a text file (invalid UTF-8)",
+            FsError::Locked        => "Encrypted volume, passphrase needed",
+            FsError::BadPassphrase => "Wrong passphrase",
+            FsError::LinkLoop      => "Too many levels of symbolic links",
+            FsError::BadTable      => "Invalid partition table",
+        };
+        f.write_str(msg)
+    }
+}
+
+// FileType enumeration: distinguishes directories, regular files and symbolic
+// links, whose content is the path they point to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Dir = 0,
+    File = 1,
+    Symlink = 2,
+}
+
+// Bit of the kind byte of a directory entry set for compressed files
+const COMPRESSED: u8 = 0x80;
+
+// Bit of the kind byte set for the names of a file with hard links, whose
+// entries point to a `Node` instead of the content
+const HARD_LINKED: u8 = 0x40;
+
+// Most symbolic links followed to resolve one path
+const MAX_SYMLINKS: usize = 16;
+
+// Extract the directory component of a pathname
+pub fn dirname(pathname: &str) -> &str {
+    let n = pathname.len();
+    let i = match pathname.rfind('/') {
+        Some(0) => 1,       // if path starts with '/', root dir
+        Some(i) => i,        // otherwise split at last '/'
+        None => n,           // no slash => empty dirname (current dir)
+    };
+    &pathname[0..i]
+}
+
+// Extract the filename component of a pathname
+pub fn filename(pathname: &str) -> &str {
+    let n = pathname.len();
+    let i = match pathname.rfind('/') {
+        Some(i) => i + 1,    // start after last '/'
+        None => 0,            // no slash => whole name
+    };
+    &pathname[i..n]
+}
+
+// Convert a relative pathname to an absolute one using current process directory
+pub fn realpath(pathname: &str) -> String {
+    let pathname = if pathname.starts_with("/") {
+        pathname.into()    // already absolute
+    } else {
+        let dirname = platform().map_or(String::from("/"), |p| (p.dir)());
+        let sep = if dirname.ends_with("/") { "" } else { "/" };
+        format!("{}{}{}", dirname, sep, pathname)
+    };
+
+    // Resolve "." and ".." components and drop empty ones (e.g. "a//b" or a trailing '/')
+    let mut components: Vec<&str> = Vec::new();
+    for name in pathname.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => { components.pop(); }
+            _ => components.push(name),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+// Resolve the symbolic links of a path into an absolute path without any, but
+// in its last component when `follow` is not set. A missing last component is
+// left as it is, so that it can be created. The target of a link is a path of
+// this volume; a relative one starts from the directory of the link.
+pub fn resolve(pathname: &str, follow: bool) -> Result<String, FsError> {
+    if !is_mounted() {
+        return Err(FsError::NotMounted);
+    }
+    let mut pathname = realpath(pathname);
+    let mut followed = 0;
+    'walk: loop {
+        let names: Vec<String> = pathname.split('/').filter(|n| !n.is_empty()).map(String::from).collect();
+        let mut dir = Dir::root();
+        for (i, name) in names.iter().enumerate() {
+            let last = i + 1 == names.len();
+            let entry = match dir.find(name) {
+                Err(FsError::NotFound) if last => break,
+                entry => entry?,
+            };
+            if entry.is_symlink() && (follow || !last) {
+                followed += 1;
+                if followed > MAX_SYMLINKS {
+                    return Err(FsError::LinkLoop);
+                }
+                let target = entry.target()?;
+                let target = if target.starts_with('/') {
+                    target
+                } else {
+                    format!("/{}/{}", names[..i].join("/"), target)
+                };
+                pathname = realpath(&format!("{}/{}", target, names[i + 1..].join("/")));
+                continue 'walk;
+            }
+            if !last {
+                if !entry.is_dir() {
+                    return Err(FsError::NotADirectory);
+                }
+                dir = entry.to_dir();
+            }
+        }
+        return Ok(pathname);
+    }
+}
+
+// Create a symbolic link at `pathname` that points to `target`, which does not
+// have to exist
+pub fn symlink(target: &str, pathname: &str) -> Result<(), FsError> {
+    if target.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    journal::transaction(|| {
+        let pathname = resolve(pathname, false)?;
+        let dir = Dir::walk(dirname(&pathname))?;
+        let entry = dir.create_entry(FileType::Symlink, filename(&pathname))?;
+        entry.to_file().write(target.as_bytes())
+    })
+}
+
+// Return the target of the symbolic link at `pathname`
+pub fn read_link(pathname: &str) -> Result<String, FsError> {
+    let pathname = resolve(pathname, false)?;
+    Dir::walk(dirname(&pathname))?.find(filename(&pathname))?.target()
+}
+
+// Give the file `src` another name, the full path `dst`, which must not exist
+// yet. The names share the content, size and times of the file, which are
+// freed with the last name.
+pub fn link(src: &str, dst: &str) -> Result<(), FsError> {
+    journal::transaction(|| {
+        let src = resolve(src, true)?;
+        let dst = resolve(dst, false)?;
+        if src == "/" {
+            return Err(FsError::IsADirectory);
+        }
+        let mut src_dir = Dir::walk(dirname(&src))?;
+        let mut entry = src_dir.find(filename(&src))?;
+        if entry.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        let dst_dir = Dir::walk(dirname(&dst))?;
+        dst_dir.check_new_name(filename(&dst))?;
+
+        // The metadata of the file moves to a node with its second name
+        let mut node = match entry.node() {
+            Some(addr) => Node::read(addr)?,
+            None => {
+                let node = Node::create(&entry)?;
+                src_dir.set_node(filename(&src), node.addr())?;
+                entry.node = node.addr();
+                node
+            }
+        };
+        node.set_links(node.links() + 1);
+        node.write()?;
+        entry.dir = dst_dir;
+        entry.name = filename(&dst).to_owned();
+        dst_dir.insert_entry(&entry)
+    })
+}
+
+// Rename or move a file or directory; `dst` is the full new path, which must not exist yet.
+// A symbolic link is moved itself, not its target.
+pub fn rename(src: &str, dst: &str) -> Result<(), FsError> {
+    let src = resolve(src, false)?;
+    let dst = resolve(dst, false)?;
+    if src == "/" || dst == "/" {
+        return Err(FsError::InvalidPath);
+    }
+    // A directory cannot be moved inside itself
+    if dst.starts_with(&format!("{}/", src)) {
+        return Err(FsError::InvalidPath);
+    }
+    let mut src_dir = Dir::walk(dirname(&src))?;
+    let mut dst_dir = Dir::walk(dirname(&dst))?;
+    src_dir.move_entry(filename(&src), &mut dst_dir, filename(&dst))
+}
+
+// Look up the directory entry of a file or directory, following symbolic links;
+// the root directory has no entry of its own, so it is returned with zero timestamps
+pub fn stat(pathname: &str) -> Result<DirEntry, FsError> {
+    let pathname = resolve(pathname, true)?;
+    let dir = Dir::walk(dirname(&pathname))?;
+    if pathname == "/" {
+        return Ok(DirEntry::new(dir, FileType::Dir, dir.addr(), 0, (0, 0, 0), "/"));
+    }
+    dir.find(filename(&pathname))
+}
+
+// Current time in Unix seconds, as stored in directory entries
+fn now() -> u64 {
+    platform().map_or(0, |p| (p.realtime)())
+}
+
+// An access time older than this is refreshed on read even if the file was not modified since
+const ATIME_INTERVAL: u64 = 24 * 60 * 60;
+
+// Representation of an open file: name, starting block address, size, and parent directory
+#[derive(Clone)]
+pub struct File {
+    name: String,
+    addr: u32,
+    size: u32,
+    created: u64,
+    modified: u64,
+    accessed: u64,
+    compressed: bool,
+    node: u32, // node of a file with hard links, 0 for the others
+    dir: Dir,  // parent directory
+    unpacked: Option<Vec<u8>>, // content of a compressed file, once decompressed
+}
+
+impl File {
+    // Create a new file at the given pathname
+    pub fn create(pathname: &str) -> Result<Self, FsError> {
+        let pathname = resolve(pathname, true)?;
+        let dirname = dirname(&pathname);
+        let filename = filename(&pathname);
+        let dir = Dir::walk(dirname)?;
+        let dir_entry = dir.create_file(filename)?;
+        Ok(dir_entry.to_file())
+    }
+
+    // Open an existing file if it exists and is a regular file, following symbolic links
+    pub fn open(pathname: &str) -> Result<Self, FsError> {
+        let pathname = resolve(pathname, true)?;
+        if pathname == "/" {
+            return Err(FsError::IsADirectory);
+        }
+        let dirname = dirname(&pathname);
+        let filename = filename(&pathname);
+        let dir = Dir::walk(dirname)?;
+        let dir_entry = dir.find(filename)?;
+        if dir_entry.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(dir_entry.to_file())
+    }
+
+    // Return file size in bytes
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    // Whether the content is stored compressed; reads and writes are the same
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    // Bytes the content takes in its chain: the size, or for a compressed file
+    // the length of the compressed data and of its 4-byte header. A length
+    // that the chain cannot hold, or that compressing `size` bytes cannot
+    // give, is damage and fails with Io before anything is allocated for it.
+    pub fn stored_size(&self) -> Result<usize, FsError> {
+        if !self.compressed {
+            return Ok(self.size());
+        }
+        let mut block = Block::read(self.addr)?;
+        let len = read_u32(block.data(), 0) as usize;
+        if len > lz4::max_compressed_size(self.size()) || self.size() > lz4::max_decompressed_size(len) {
+            return Err(FsError::Io);
+        }
+        for _ in 0..(4 + len - 1) / DATA_SIZE {
+            block = block.next()?.ok_or(FsError::Io)?;
+        }
+        Ok(4 + len)
+    }
+
+    // Length of the header and compressed data of a compressed file, as its
+    // first block records it, without any check
+    pub(crate) fn header_len(&self) -> Result<usize, FsError> {
+        let block = Block::read(self.addr)?;
+        Ok(4 + read_u32(block.data(), 0) as usize)
+    }
+
+    // Timestamps in Unix seconds
+    pub fn created(&self) -> u64 { self.created }
+    pub fn modified(&self) -> u64 { self.modified }
+    pub fn accessed(&self) -> u64 { self.accessed }
+
+    // Read file data into provided buffer, returning number of bytes read
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.read_at(0, buf)
+    }
+
+    // Read file data starting at `offset` into buf, returning number of bytes read
+    pub fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.touch_accessed()?;
+        if offset >= self.size() {
+            return Ok(0);
+        }
+        // stop if buffer full or reached file size
+        let n = buf.len().min(self.size() - offset);
+        if self.compressed {
+            let data = self.load()?;
+            buf[..n].copy_from_slice(&data[offset..offset + n]);
+            return Ok(n);
+        }
+        self.read_chain(offset, &mut buf[..n])?;
+        Ok(n)
+    }
+
+    // Fill `buf` with the bytes of the chain from `offset`
+    fn read_chain(&self, offset: usize, buf: &mut [u8]) -> Result<(), FsError> {
+        let n = buf.len();
+
+        // skip the blocks before offset
+        let mut block = Block::read(self.addr)?;
+        for _ in 0..offset / DATA_SIZE {
+            block = block.next()?.ok_or(FsError::Io)?; // chain shorter than file size
+        }
+
+        let mut i = 0;
+        let mut j = offset % DATA_SIZE;
+        loop {
+            let k = (DATA_SIZE - j).min(n - i);
+            buf[i..i + k].copy_from_slice(&block.data()[j..j + k]);
+            i += k;
+            if i == n {
+                return Ok(());
+            }
+            block = block.next()?.ok_or(FsError::Io)?;
+            j = 0;
+        }
+    }
+
+    // Read entire file into a UTF-8 string, or fail with InvalidData for
+    // binary content
+    pub fn read_to_string(&mut self) -> Result<String, FsError> {
+        let mut buf = vec![0; self.size()];
+        let bytes = self.read(&mut buf)?;
+        buf.resize(bytes, 0);
+        String::from_utf8(buf).map_err(|_| FsError::InvalidData)
+    }
+
+    // Write buffer to file, allocating or freeing blocks as needed
+    pub fn write(&mut self, buf: &[u8]) -> Result<(), FsError> {
+        journal::transaction(|| {
+            if self.compressed {
+                return self.rewrite(buf);
+            }
+            self.write_at(0, buf)?;
+            self.truncate(buf.len())
+        })
+    }
+
+    // Write buffer at `offset`, extending the chain when writing past its last block.
+    // A gap between the end of the file and `offset` is filled with zeros.
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        journal::transaction(|| {
+            let end = offset + buf.len();
+            if end > u32::MAX as usize {
+                return Err(FsError::NoSpace);
+            }
+            let start = offset.min(self.size());
+            if start == end {
+                return Ok(0);
+            }
+
+            // A compressed file is rewritten as a whole
+            if self.compressed {
+                let mut data = self.load()?.to_vec();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset..end].copy_from_slice(buf);
+                self.rewrite(&data)?;
+                return Ok(buf.len());
+            }
+
+            self.write_chain(self.size(), offset, buf)?;
+
+            // update file metadata
+            self.size = self.size.max(end as u32);
+            self.modified = now();
+            self.save_entry()?;
+            Ok(buf.len())
+        })
+    }
+
+    // Write `buf` to the chain at `offset`, where the chain holds `len` bytes.
+    // New blocks are written in place rather than journaled (see `journal`),
+    // and so are the blocks after the first one that already held data: they
+    // are moved to new blocks, so that a crash leaves the old content, or
+    // overwritten where they are when the volume has no room for the copies.
+    fn write_chain(&mut self, len: usize, offset: usize, buf: &[u8]) -> Result<(), FsError> {
+        let end = offset + buf.len();
+        let start = offset.min(len);
+        if start == end {
+            return Ok(());
+        }
+
+        // Fail before staging anything when the new blocks cannot fit
+        let blocks = |n: usize| (n / DATA_SIZE + (n % DATA_SIZE != 0) as usize).max(1);
+        let first = start / DATA_SIZE; // first block written
+        let needed = blocks(end).saturating_sub(blocks(len));
+        let moved = blocks(len).min(blocks(end)).saturating_sub(first.max(1));
+        let free = BlockBitmap::free_count()? as usize;
+        if needed > free {
+            return Err(FsError::NoSpace);
+        }
+        let relocate = needed + moved <= free;
+
+        // skip the blocks before start, linking a new one if start is at the end of the chain
+        let mut block = Block::read(self.addr)?;
+        for i in 0..first {
+            let (next_block, linked) = block.next_to_write(relocate && i + 1 == first)?;
+            if linked {
+                block.write()?;
+            }
+            block = next_block;
+        }
+
+        // fill blocks with zeros up to offset, then with data
+        let mut index = first;
+        let mut pos = start;
+        loop {
+            let j = pos % DATA_SIZE;
+            let k = (DATA_SIZE - j).min(end - pos);
+            let data = block.data_mut();
+            for l in 0..k {
+                data[j + l] = if pos + l < offset { 0 } else { buf[pos + l - offset] };
+            }
+            pos += k;
+            // update block chaining and write to disk
+            let next = if pos < end { Some(block.next_to_write(relocate)?) } else { None };
+            // Without room to move it, a block that held data is overwritten
+            // in place, unless it now points elsewhere
+            if !relocate && index > 0 && !matches!(next, Some((_, true))) {
+                journal::overwrite(block.addr());
+            }
+            block.write()?;
+            match next {
+                Some((next_block, _)) => block = next_block,
+                None => return Ok(()),
+            }
+            index += 1;
+        }
+    }
+
+    // Shrink the file to `len` bytes and return the blocks past the new end to the bitmap
+    pub fn truncate(&mut self, len: usize) -> Result<(), FsError> {
+        journal::transaction(|| {
+            if len >= self.size() {
+                return Ok(());
+            }
+            if self.compressed {
+                let mut data = if len == 0 { Vec::new() } else { self.load()?.to_vec() };
+                data.truncate(len);
+                return self.rewrite(&data);
+            }
+            self.truncate_chain(len)?;
+
+            // update file metadata
+            self.size = len as u32;
+            self.modified = now();
+            self.save_entry()
+        })
+    }
+
+    // Free the blocks of the chain past its first `len` bytes
+    fn truncate_chain(&mut self, len: usize) -> Result<(), FsError> {
+        // The first block is kept even when the file becomes empty
+        let last = if len == 0 { 0 } else { (len - 1) / DATA_SIZE };
+        let mut block = Block::read(self.addr)?;
+        for _ in 0..last {
+            block = block.next()?.ok_or(FsError::Io)?;
+        }
+        let rest = block.next_addr();
+        if rest != 0 {
+            block.set_next(0);
+            block.write()?;
+            BlockBitmap::free_chain(rest)?;
+        }
+        Ok(())
+    }
+
+    // Resize the file to `len` bytes, either truncating it or padding it with zeros
+    pub fn set_len(&mut self, len: usize) -> Result<(), FsError> {
+        if len < self.size() {
+            self.truncate(len)
+        } else {
+            self.write_at(len, &[]).map(|_| ())
+        }
+    }
+
+    // Turn compression of the file on or off, rewriting its content in the new form
+    pub fn set_compressed(&mut self, compressed: bool) -> Result<(), FsError> {
+        journal::transaction(|| {
+            if compressed == self.compressed {
+                return Ok(());
+            }
+            let old = self.stored_size()?;
+            let data = if self.compressed {
+                self.load()?.to_vec()
+            } else {
+                let mut data = vec![0; self.size()];
+                self.read_chain(0, &mut data)?;
+                data
+            };
+            self.compressed = compressed;
+            self.store(old, &data)?;
+            self.save_entry()
+        })
+    }
+
+    // Decompress the content of a compressed file, once: it is kept until the
+    // content changes. Its chain holds the length of the compressed data (4
+    // bytes) followed by the data.
+    fn load(&mut self) -> Result<&[u8], FsError> {
+        if self.unpacked.is_none() {
+            let mut packed = vec![0; self.stored_size()?];
+            self.read_chain(0, &mut packed)?;
+            self.unpacked = Some(lz4::decompress(&packed[4..], self.size())?);
+        }
+        Ok(self.unpacked.as_deref().unwrap_or_default())
+    }
+
+    // Write `data` as the content of the file, compressed if the file is, and
+    // free the blocks that are no longer used; the chain holds `old` bytes
+    fn store(&mut self, old: usize, data: &[u8]) -> Result<(), FsError> {
+        let mut packed = Vec::new();
+        let chain = if self.compressed {
+            let compressed = lz4::compress(data);
+            packed.resize(4, 0);
+            write_u32(&mut packed, 0, compressed.len() as u32);
+            packed.extend_from_slice(&compressed);
+            &packed[..]
+        } else {
+            data
+        };
+        self.unpacked = None;
+        self.write_chain(old, 0, chain)?;
+        self.truncate_chain(chain.len())?;
+        self.size = data.len() as u32;
+        if self.compressed {
+            self.unpacked = Some(data.to_vec());
+        }
+        Ok(())
+    }
+
+    // Replace the whole content of a compressed file
+    fn rewrite(&mut self, data: &[u8]) -> Result<(), FsError> {
+        if data.len() > u32::MAX as usize {
+            return Err(FsError::NoSpace);
+        }
+        let old = self.stored_size()?;
+        self.store(old, data)?;
+        self.modified = now();
+        self.save_entry()
+    }
+
+    // Refresh the access time, but only when the file was modified since the last
+    // access or the last access is old, so that reads rarely cost a directory write.
+    // A device that cannot be written keeps the old time.
+    fn touch_accessed(&mut self) -> Result<(), FsError> {
+        let now = now();
+        if now == self.accessed || is_read_only() {
+            return Ok(());
+        }
+        if self.accessed <= self.modified || now >= self.accessed + ATIME_INTERVAL {
+            let accessed = self.accessed;
+            self.accessed = now;
+            if let Err(e) = self.save_entry() {
+                self.accessed = accessed;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Write size, compression and timestamps back to the entry in the parent
+    // directory, or to the node shared by the names of the file
+    fn save_entry(&mut self) -> Result<(), FsError> {
+        if self.node != 0 {
+            let mut node = Node::read(self.node)?;
+            node.save(self);
+            return node.write();
+        }
+        self.dir.update_entry(&self.name, self.size, self.compressed, self.modified, self.accessed)
+    }
+
+    // Return starting block address of file
+    pub fn addr(&self) -> u32 {
+        self.addr
+    }
+
+    // Delete a file, or a symbolic link itself, by pathname
+    pub fn delete(pathname: &str) -> Result<(), FsError> {
+        let pathname = resolve(pathname, false)?;
+        let dirname = dirname(&pathname);
+        let filename = filename(&pathname);
+        let mut dir = Dir::walk(dirname)?;
+        if dir.find(filename)?.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        dir.delete_entry(filename)
+    }
+}
+
+// Origin of a FileHandle::seek, like std::io::SeekFrom
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(usize),
+    End(isize),
+    Current(isize),
+}
+
+// Open file with a cursor: sequential reads and writes continue where the last one stopped
+#[derive(Clone)]
+pub struct FileHandle {
+    file: File,
+    offset: usize,
+}
+
+impl FileHandle {
+    // Open an existing file with the cursor at its start
+    pub fn open(pathname: &str) -> Result<Self, FsError> {
+        Ok(Self::new(File::open(pathname)?))
+    }
+
+    pub fn new(file: File) -> Self {
+        Self { file, offset: 0 }
+    }
+
+    pub fn file(&self) -> &File { &self.file }
+    pub fn offset(&self) -> usize { self.offset }
+    pub fn size(&self) -> usize { self.file.size() }
+
+    // Move the cursor and return its new offset; it may go past the end of the file
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<usize, FsError> {
+        let offset = match pos {
+            SeekFrom::Start(n) => n as isize,
+            SeekFrom::End(n) => self.size() as isize + n,
+            SeekFrom::Current(n) => self.offset as isize + n,
+        };
+        if offset < 0 {
+            return Err(FsError::InvalidOffset);
+        }
+        self.offset = offset as usize;
+        Ok(self.offset)
+    }
+
+    // Read at the cursor and advance it
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let n = self.file.read_at(self.offset, buf)?;
+        self.offset += n;
+        Ok(n)
+    }
+
+    // Write at the cursor and advance it
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        let n = self.file.write_at(self.offset, buf)?;
+        self.offset += n;
+        Ok(n)
+    }
+
+    // Read at `offset` without moving the cursor
+    pub fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.file.read_at(offset, buf)
+    }
+
+    // Write at `offset` without moving the cursor
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        self.file.write_at(offset, buf)
+    }
+
+    // Write at the end of the file, leaving the cursor there
+    pub fn append(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        let n = self.file.write_at(self.file.size(), buf)?;
+        self.offset = self.file.size();
+        Ok(n)
+    }
+
+    // Shrink the file to `len` bytes, the cursor is left where it is
+    pub fn truncate(&mut self, len: usize) -> Result<(), FsError> {
+        self.file.truncate(len)
+    }
+
+    // Truncate or zero-extend the file to `len` bytes
+    pub fn set_len(&mut self, len: usize) -> Result<(), FsError> {
+        self.file.set_len(len)
+    }
+}
+
+// Data bytes in a block, after the 4-byte next pointer and the 4-byte checksum
+pub const DATA_SIZE: usize = 512 - 8;
+
+// 512-byte block: 4-byte next pointer + 4-byte checksum + 504-byte data
+//
+// The checksum is set when the block is written and verified when it is read
+// from the cache, so a block changed on disk behind the file system reads as
+// an I/O error.
+#[derive(Clone)]
+pub struct Block {
+    addr: u32,
+    buf: [u8; 512],
+}
+
+impl Block {
+    // Create an empty block buffer at given address
+    pub fn new(addr: u32) -> Self {
+        let buf = [0; 512];
+        Self { addr, buf }
+    }
+
+    // Read block data through the cache, or from the journal if the current
+    // transaction has already written it; a block with a wrong checksum
+    // fails with Io
+    pub fn read(addr: u32) -> Result<Self, FsError> {
+        if let Some(buf) = journal::staged(addr) {
+            return Ok(Self { addr, buf });
+        }
+        let mut buf = [0; 512];
+        cache::read(addr, &mut buf)?;
+        if read_u32(&buf, 4) != checksum(addr, &buf) {
+            return Err(FsError::Io);
+        }
+        Ok(Self { addr, buf })
+    }
+
+    // Allocate a free block using the bitmap
+    pub fn alloc() -> Result<Self, FsError> {
+        let addr = BlockBitmap::next_free_addr()?;
+        BlockBitmap::alloc(addr)?;
+        journal::allocated(addr);
+        // zero-initialize
+        let block = Block::new(addr);
+        block.write()?;
+        Ok(block)
+    }
+
+    // Write block buffer through the journal: it is written in place or staged
+    // until the current transaction ends, or committed on its own outside of a
+    // transaction
+    pub fn write(&self) -> Result<(), FsError> {
+        let buf = self.encode();
+        journal::transaction(|| journal::write(self.addr, &buf))
+    }
+
+    // Copy the block to a newly allocated one, which the current transaction
+    // writes in place, and free this one, which a crash leaves as it was
+    fn relocate(&self) -> Result<Self, FsError> {
+        let mut copy = Block::alloc()?;
+        copy.buf = self.buf;
+        BlockBitmap::free(self.addr)?;
+        Ok(copy)
+    }
+
+    // The block as stored on disk, with its checksum
+    fn encode(&self) -> [u8; 512] {
+        let mut buf = self.buf;
+        write_u32(&mut buf, 4, checksum(self.addr, &self.buf));
+        buf
+    }
+
+    // Return block address
+    pub fn addr(&self) -> u32 { self.addr }
+
+    // Return immutable view of data region
+    pub fn data(&self) -> &[u8] {
+        &self.buf[8..512]
+    }
+
+    // Return mutable view of data region
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[8..512]
+    }
+
+    // Read next chained block if present
+    pub fn next(&self) -> Result<Option<Self>, FsError> {
+        let addr = self.next_addr();
+        if addr == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(Self::read(addr)?))
+        }
+    }
+
+    // Return the next block pointer (0 at the end of the chain)
+    pub fn next_addr(&self) -> u32 {
+        read_u32(&self.buf, 0)
+    }
+
+    // Next block of a chain being written: a new one linked at the end of the
+    // chain, or with `relocate` a copy of the next one, unless the current
+    // transaction already writes that one in place. Also tells whether this
+    // block now points elsewhere, and has to be written back.
+    fn next_to_write(&mut self, relocate: bool) -> Result<(Self, bool), FsError> {
+        match self.next()? {
+            Some(next_block) if relocate && !journal::in_place(next_block.addr()) => {
+                let copy = next_block.relocate()?;
+                self.set_next(copy.addr());
+                Ok((copy, true))
+            }
+            Some(next_block) => Ok((next_block, false)),
+            None => {
+                let next_block = Block::alloc()?;
+                self.set_next(next_block.addr());
+                Ok((next_block, true))
+            }
+        }
+    }
+
+    // Set next block pointer
+    pub fn set_next(&mut self, addr: u32) {
+        write_u32(&mut self.buf, 0, addr);
+    }
+}
+
+// CRC-32 (IEEE) lookup table, computed at compile time
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            k += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// Continue a CRC-32 with more bytes; start from !0 and invert the result
+pub(crate) fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &b in bytes {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+// Checksum of a block: CRC-32 of its address, next pointer and data, so that
+// a block written at the wrong address does not pass either
+fn checksum(addr: u32, buf: &[u8]) -> u32 {
+    let mut crc = crc32(!0, &addr.to_be_bytes());
+    crc = crc32(crc, &buf[0..4]);
+    !crc32(crc, &buf[8..512])
+}
+
+// Read a big-endian u32 stored at buf[i..i + 4]
+fn read_u32(buf: &[u8], i: usize) -> u32 {
+    (buf[i] as u32) << 24
+        | (buf[i + 1] as u32) << 16
+        | (buf[i + 2] as u32) << 8
+        | (buf[i + 3] as u32)
+}
+
+// Write a u32 as big-endian into buf[i..i + 4]
+fn write_u32(buf: &mut [u8], i: usize, val: u32) {
+    buf[i] = val.get_bits(24..32) as u8;
+    buf[i + 1] = val.get_bits(16..24) as u8;
+    buf[i + 2] = val.get_bits(8..16) as u8;
+    buf[i + 3] = val.get_bits(0..8) as u8;
+}
+
+// Read a big-endian u64 stored at buf[i..i + 8]
+fn read_u64(buf: &[u8], i: usize) -> u64 {
+    (read_u32(buf, i) as u64) << 32 | read_u32(buf, i + 4) as u64
+}
+
+// Write a u64 as big-endian into buf[i..i + 8]
+fn write_u64(buf: &mut [u8], i: usize, val: u64) {
+    write_u32(buf, i, (val >> 32) as u32);
+    write_u32(buf, i + 4, val as u32);
+}
+
+// Disk layout parameters
+pub const BLOCK_SIZE: u32 = 512;
+const BITMAP_SIZE: u32 = BLOCK_SIZE - 8; // data bytes in bitmap block
+const DISK_OFFSET: u32 = (1 << 20) / 512; // first MiB is reserved for the boot image
+pub const SUPERBLOCK_ADDR: u32 = DISK_OFFSET;
+const JOURNAL_SIZE: u32 = 512; // most blocks reserved for the journal by `format`
+
+// Superblock: self-description of a volume, stored at SUPERBLOCK_ADDR
+//
+// | magic (8) | version (1) | reserved (3) | block size (4) | block count (4) |
+// | bitmap addr (4) | data addr (4) | root dir addr (4) | journal addr (4) |
+// | cipher (1) | reserved (3) | KDF iterations (4) | salt (16) | key check (16) | zero padding |
+//
+// Numbers are big-endian. Cipher 0 is a clear volume and 1 one encrypted by
+// `crypt`, whose other fields are then used to derive and check the key. The volume spans blocks 0..block_count of the disk,
+// the journal runs from journal addr to bitmap addr, bitmap blocks sit between
+// bitmap addr and data addr and the data region runs from data addr to the
+// end of the volume.
+#[derive(Debug, Clone, Copy)]
+pub struct Superblock {
+    version: u8,
+    block_size: u32,
+    block_count: u32,
+    journal_addr: u32,
+    bitmap_addr: u32,
+    data_addr: u32,
+    root_addr: u32,
+    encryption: Option<Encryption>,
+}
+
+// How the key of an encrypted volume is derived from its passphrase
+#[derive(Debug, Clone, Copy)]
+pub struct Encryption {
+    pub iterations: u32,
+    pub salt: [u8; 16],
+    pub check: [u8; 16],
+}
+
+impl Superblock {
+    // Compute the layout of a new volume spanning `block_count` blocks of a disk
+    pub fn new(block_count: u32) -> Self {
+        // Small volumes such as RAM disks get a smaller journal, down to what
+        // the largest transaction needs
+        let volume_size = block_count.saturating_sub(SUPERBLOCK_ADDR);
+        let journal_size = (volume_size / 16).min(JOURNAL_SIZE).max(journal::MIN_SIZE);
+        let journal_addr = SUPERBLOCK_ADDR + 1;
+        let bitmap_addr = journal_addr + journal_size;
+        // Each bitmap block tracks BITMAP_SIZE * 8 data blocks, so split the
+        // remaining space between bitmap and data blocks accordingly
+        let blocks = block_count.saturating_sub(bitmap_addr);
+        let bits = BITMAP_SIZE * 8;
+        let bitmap_count = (blocks + bits) / (bits + 1);
+        let data_addr = bitmap_addr + bitmap_count;
+        Self {
+            version: VERSION,
+            block_size: BLOCK_SIZE,
+            block_count,
+            journal_addr,
+            bitmap_addr,
+            data_addr,
+            root_addr: data_addr,
+            encryption: None,
+        }
+    }
+
+    // Read the superblock of a disk, failing if it has no ParvaFS magic
+    pub fn read(block_device: &mut dyn BlockDevice) -> Result<Self, FsError> {
+        let mut buf = [0u8; 512];
+        block_device.read_block(SUPERBLOCK_ADDR, &mut buf)?;
+        if &buf[0..8] != MAGIC.as_bytes() {
+            return Err(FsError::Unsupported);
+        }
+        let encryption = match buf[36] {
+            0 => None,
+            1 => {
+                let mut enc = Encryption { iterations: read_u32(&buf, 40), salt: [0; 16], check: [0; 16] };
+                enc.salt.copy_from_slice(&buf[44..60]);
+                enc.check.copy_from_slice(&buf[60..76]);
+                Some(enc)
+            }
+            _ => return Err(FsError::Unsupported),
+        };
+        Ok(Self {
+            version: buf[8],
+            block_size: read_u32(&buf, 12),
+            block_count: read_u32(&buf, 16),
+            bitmap_addr: read_u32(&buf, 20),
+            data_addr: read_u32(&buf, 24),
+            root_addr: read_u32(&buf, 28),
+            journal_addr: read_u32(&buf, 32),
+            encryption,
+        })
+    }
+
+    // Write the superblock to a disk
+    pub fn write(&self, block_device: &mut dyn BlockDevice) -> Result<(), FsError> {
+        let mut buf = [0u8; 512];
+        buf[0..8].copy_from_slice(MAGIC.as_bytes());
+        buf[8] = self.version;
+        write_u32(&mut buf, 12, self.block_size);
+        write_u32(&mut buf, 16, self.block_count);
+        write_u32(&mut buf, 20, self.bitmap_addr);
+        write_u32(&mut buf, 24, self.data_addr);
+        write_u32(&mut buf, 28, self.root_addr);
+        write_u32(&mut buf, 32, self.journal_addr);
+        if let Some(enc) = self.encryption {
+            buf[36] = 1;
+            write_u32(&mut buf, 40, enc.iterations);
+            buf[44..60].copy_from_slice(&enc.salt);
+            buf[60..76].copy_from_slice(&enc.check);
+        }
+        block_device.write_block(SUPERBLOCK_ADDR, &buf)
+    }
+
+    // Check that this driver understands the on-disk format
+    pub fn is_supported(&self) -> bool {
+        self.version == VERSION
+            && self.block_size == BLOCK_SIZE
+            && self.journal_addr > SUPERBLOCK_ADDR
+            && self.journal_addr + journal::MIN_SIZE <= self.bitmap_addr
+            && self.bitmap_addr <= self.data_addr
+            && self.data_addr <= self.root_addr
+            && self.root_addr < self.block_count
+    }
+
+    pub fn version(&self) -> u8 { self.version }
+    pub fn block_size(&self) -> u32 { self.block_size }
+    pub fn block_count(&self) -> u32 { self.block_count }
+    pub fn journal_addr(&self) -> u32 { self.journal_addr }
+
+    // Number of journal blocks
+    pub fn journal_count(&self) -> u32 {
+        self.bitmap_addr - self.journal_addr
+    }
+    pub fn bitmap_addr(&self) -> u32 { self.bitmap_addr }
+    pub fn data_addr(&self) -> u32 { self.data_addr }
+    pub fn root_addr(&self) -> u32 { self.root_addr }
+    pub fn encryption(&self) -> Option<Encryption> { self.encryption }
+    pub fn is_encrypted(&self) -> bool { self.encryption.is_some() }
+
+    // Number of bitmap blocks
+    pub fn bitmap_count(&self) -> u32 {
+        self.data_addr - self.bitmap_addr
+    }
+
+    // Number of data blocks tracked by the bitmap
+    pub fn data_count(&self) -> u32 {
+        self.block_count - self.data_addr
+    }
+}
+
+// Return the superblock of the mounted volume
+pub fn superblock() -> Result<Superblock, FsError> {
+    volume().superblock.lock().ok_or(FsError::NotMounted)
+}
+
+// BlockBitmap: manage allocation status of data blocks via bitmap stored on disk
+pub struct BlockBitmap {}
+
+// In-memory summary of the bitmap, loaded at mount and kept up to date by
+// `BlockBitmap::alloc` and `BlockBitmap::free`
+struct FreeSpace {
+    count: u32, // free data blocks
+    hint: u32,  // where the next search for a free block starts
+}
+
+impl BlockBitmap {
+    // Compute bitmap block index for a data block address
+    fn block_index(sb: &Superblock, data_addr: u32) -> u32 {
+        let i = data_addr - sb.data_addr();
+        sb.bitmap_addr() + (i / BITMAP_SIZE / 8)
+    }
+
+    // Compute bit offset inside bitmap block
+    fn buffer_index(sb: &Superblock, data_addr: u32) -> usize {
+        let i = data_addr - sb.data_addr();
+        (i % (BITMAP_SIZE * 8)) as usize
+    }
+
+    // Check if a block is free
+    pub fn is_free(addr: u32) -> Result<bool, FsError> {
+        let sb = superblock()?;
+        let block = Block::read(BlockBitmap::block_index(&sb, addr))?;
+        let bitmap = block.data();
+        let i = BlockBitmap::buffer_index(&sb, addr);
+        Ok(!bitmap[i / 8].get_bit(i % 8))
+    }
+
+    // Mark a block as allocated
+    pub fn alloc(addr: u32) -> Result<(), FsError> {
+        let sb = superblock()?;
+        let mut block = Block::read(BlockBitmap::block_index(&sb, addr))?;
+        let bitmap = block.data_mut();
+        let i = BlockBitmap::buffer_index(&sb, addr);
+        if bitmap[i / 8].get_bit(i % 8) {
+            return Ok(());
+        }
+        bitmap[i / 8].set_bit(i % 8, true);
+        block.write()?;
+        let volume = volume();
+        let mut free_space = volume.free_space.lock();
+        free_space.count = free_space.count.saturating_sub(1);
+        free_space.hint = addr + 1;
+        Ok(())
+    }
+
+    // Mark a block as free
+    pub fn free(addr: u32) -> Result<(), FsError> {
+        let sb = superblock()?;
+        let mut block = Block::read(BlockBitmap::block_index(&sb, addr))?;
+        let bitmap = block.data_mut();
+        let i = BlockBitmap::buffer_index(&sb, addr);
+        if !bitmap[i / 8].get_bit(i % 8) {
+            return Ok(());
+        }
+        bitmap[i / 8].set_bit(i % 8, false);
+        block.write()?;
+        journal::freed(addr);
+        volume().free_space.lock().count += 1;
+        Ok(())
+    }
+
+    // Free every block of the chain starting at `addr`
+    pub fn free_chain(addr: u32) -> Result<(), FsError> {
+        let mut blk = Block::read(addr)?;
+        loop {
+            BlockBitmap::free(blk.addr())?;
+            match blk.next()? {
+                Some(nb) => blk = nb,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Count the free data blocks and reset the allocation hint, called on mount
+    pub fn load() -> Result<(), FsError> {
+        let sb = superblock()?;
+        let bits = BITMAP_SIZE * 8;
+        let mut count = 0;
+        for i in 0..sb.bitmap_count() {
+            let block = Block::read(sb.bitmap_addr() + i)?;
+            let bitmap = block.data();
+            // The last bitmap block is only partly used
+            let n = sb.data_count().saturating_sub(i * bits).min(bits) as usize;
+            count += (0..n).filter(|&j| !bitmap[j / 8].get_bit(j % 8)).count() as u32;
+        }
+        *volume().free_space.lock() = FreeSpace { count, hint: sb.data_addr() };
+        Ok(())
+    }
+
+    // Number of free data blocks
+    pub fn free_count() -> Result<u32, FsError> {
+        superblock()?;
+        Ok(volume().free_space.lock().count)
+    }
+
+    // Find the next free data block address, starting from the block after
+    // the last one allocated and wrapping around at the end of the volume
+    pub fn next_free_addr() -> Result<u32, FsError> {
+        let sb = superblock()?;
+        let (count, hint) = {
+            let volume = volume();
+            let free_space = volume.free_space.lock();
+            (free_space.count, free_space.hint)
+        };
+        if count == 0 || sb.data_count() == 0 {
+            return Err(FsError::NoSpace);
+        }
+        let bits = BITMAP_SIZE * 8;
+        let start = hint.saturating_sub(sb.data_addr()) % sb.data_count();
+        let n = sb.bitmap_count();
+        // The bitmap block of the hint is visited twice: from the hint the
+        // first time, and from its start after wrapping around
+        for k in 0..(n + 1) {
+            let i = (start / bits + k) % n;
+            let block = Block::read(sb.bitmap_addr() + i)?;
+            let bitmap = block.data();
+            let end = sb.data_count().saturating_sub(i * bits).min(bits);
+            let mut j = if k == 0 { start % bits } else { 0 };
+            while j < end {
+                if j % 8 == 0 && bitmap[(j / 8) as usize] == 0xFF {
+                    j += 8; // skip a byte of allocated blocks
+                    continue;
+                }
+                if !bitmap[(j / 8) as usize].get_bit((j % 8) as usize) {
+                    return Ok(sb.data_addr() + i * bits + j);
+                }
+                j += 1;
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+}
+
+// Directory entry metadata: parent Dir, type, address, size, and name
+#[derive(Clone)]
+pub struct DirEntry {
+    dir: Dir,
+    kind: FileType,
+    addr: u32,
+    size: u32,
+    created: u64,
+    modified: u64,
+    accessed: u64,
+    compressed: bool,
+    node: u32,  // node of a file with hard links, 0 for the others
+    links: u32, // names of the file
+    name: String,
+}
+
+// Bytes of a directory entry before its name:
+// kind(1, with the COMPRESSED and HARD_LINKED bits) | addr(4) | size(4) | created(8) | modified(8) | accessed(8) | name length(1)
+// The addr of a hard-linked entry is that of its node, which holds the size and times.
+const DIR_ENTRY_HEADER: usize = 1 + 4 + 4 + 8 + 8 + 8 + 1;
+
+impl DirEntry {
+    // Construct a new DirEntry
+    pub fn new(dir: Dir, kind: FileType, addr: u32, size: u32, times: (u64, u64, u64), name: &str) -> Self {
+        let name = name.to_owned();
+        let (created, modified, accessed) = times;
+        Self { dir, kind, addr, size, created, modified, accessed, compressed: false, node: 0, links: 1, name }
+    }
+    // Check if entry is directory
+    pub fn is_dir(&self) -> bool { self.kind == FileType::Dir }
+    // Check if entry is file
+    pub fn is_file(&self) -> bool { self.kind == FileType::File }
+    // Check if entry is a symbolic link; its size is the length of the target
+    pub fn is_symlink(&self) -> bool { self.kind == FileType::Symlink }
+    pub fn kind(&self) -> FileType { self.kind }
+    pub fn size(&self) -> u32 { self.size }
+    // Check if entry is a compressed file; its size is that of the content
+    pub fn is_compressed(&self) -> bool { self.compressed }
+    pub fn name(&self) -> String { self.name.clone() }
+    // First block of the entry's data (or of the subdirectory)
+    pub fn addr(&self) -> u32 { self.addr }
+    // Node of a file with hard links, and number of names of the file
+    pub fn node(&self) -> Option<u32> { if self.node != 0 { Some(self.node) } else { None } }
+    pub fn links(&self) -> u32 { self.links }
+    // Timestamps in Unix seconds
+    pub fn created(&self) -> u64 { self.created }
+    pub fn modified(&self) -> u64 { self.modified }
+    pub fn accessed(&self) -> u64 { self.accessed }
+    // Convert entry to Dir object
+    pub fn to_dir(&self) -> Dir {
+        assert!(self.kind == FileType::Dir);
+        Dir { addr: self.addr }
+    }
+    // Convert entry to File object; the content of a symbolic link is read and
+    // written like that of a file
+    pub fn to_file(&self) -> File {
+        assert!(self.kind != FileType::Dir);
+        File {
+            name: self.name.clone(),
+            addr: self.addr,
+            size: self.size,
+            created: self.created,
+            modified: self.modified,
+            accessed: self.accessed,
+            compressed: self.compressed,
+            node: self.node,
+            dir: self.dir,
+            unpacked: None,
+        }
+    }
+    // Path a symbolic link points to
+    pub fn target(&self) -> Result<String, FsError> {
+        if !self.is_symlink() {
+            return Err(FsError::InvalidPath);
+        }
+        let mut buf = vec![0; self.size as usize];
+        self.to_file().read_chain(0, &mut buf)?;
+        String::from_utf8(buf).map_err(|_| FsError::InvalidData)
+    }
+    // Bytes the content takes on disk, see File::stored_size
+    pub fn stored_size(&self) -> Result<u32, FsError> {
+        if self.is_dir() {
+            return Ok(self.size);
+        }
+        self.to_file().stored_size().map(|n| n as u32)
+    }
+    // First byte of the entry on disk
+    fn kind_byte(&self) -> u8 {
+        self.kind as u8 | if self.compressed { COMPRESSED } else { 0 } | if self.node != 0 { HARD_LINKED } else { 0 }
+    }
+    // Address stored in the entry on disk
+    fn stored_addr(&self) -> u32 {
+        if self.node != 0 { self.node } else { self.addr }
+    }
+    // Compute byte length of entry on disk
+    fn len(&self) -> usize {
+        DIR_ENTRY_HEADER + self.name.len()
+    }
+    // Write the entry at offset `i` of the data of a directory block
+    fn encode(&self, data: &mut [u8], i: usize) {
+        let entry_name  = self.name.as_bytes();
+        let n           = entry_name.len();
+
+        // Write entry header:
+        data[i] = self.kind_byte();                       // FileType and flags
+        // 4-byte big-endian addr of first block, or of the node
+        write_u32(data, i + 1, self.stored_addr());
+        // 4-byte size
+        write_u32(data, i + 5, self.size);
+        // 8-byte created, modified and accessed times
+        write_u64(data, i + 9, self.created);
+        write_u64(data, i + 17, self.modified);
+        write_u64(data, i + 25, self.accessed);
+        // Name length
+        data[i + 33] = n as u8;
+        // Name bytes
+        data[i + DIR_ENTRY_HEADER..i + DIR_ENTRY_HEADER + n].copy_from_slice(entry_name);
+    }
+}
+
+// Offset, length and state (in use or deleted) of each entry in the data of a
+// directory block, and the offset where the free space after them starts
+fn slots(data: &[u8]) -> (Vec<(usize, usize, bool)>, usize) {
+    let mut slots = Vec::new();
+    let mut i = 0;
+    while i < data.len() - DIR_ENTRY_HEADER {
+        if data[i] & !(COMPRESSED | HARD_LINKED) > FileType::Symlink as u8 {
+            break;
+        }
+        let n = data[i + 33] as usize;
+        if n == 0 || i + DIR_ENTRY_HEADER + n > data.len() {
+            break;
+        }
+        slots.push((i, DIR_ENTRY_HEADER + n, read_u32(data, i + 1) != 0));
+        i += DIR_ENTRY_HEADER + n;
+    }
+    (slots, i)
+}
+
+// Directory abstraction managing entries by chaining blocks together
+#[derive(Clone, Copy)]
+pub struct Dir {
+    addr: u32, // Starting block address of this directory
+}
+
+impl Dir {
+    // Return the root directory, whose address is recorded in the superblock
+    pub fn root() -> Self {
+        let addr = superblock().map_or(0, |sb| sb.root_addr());
+        Self { addr }
+    }
+
+    // Create a new directory at the given (possibly relative) path
+    pub fn create(pathname: &str) -> Result<Self, FsError> {
+        let pathname = resolve(pathname, false)?;        // Make absolute
+        let dirname = dirname(&pathname);                // Parent path
+        let filename = filename(&pathname);              // New dir name
+        // If parent exists, create the new subdirectory entry
+        let dir = Dir::walk(dirname)?;
+        let entry = dir.create_dir(filename)?;
+        Ok(entry.to_dir())
+    }
+
+    // Open an existing directory, following symbolic links
+    pub fn open(pathname: &str) -> Result<Self, FsError> {
+        Self::walk(&resolve(pathname, true)?)
+    }
+
+    // Open a directory by walking each component of a resolved path from root
+    fn walk(pathname: &str) -> Result<Self, FsError> {
+        if !is_mounted() {                               // FS must be mounted
+            return Err(FsError::NotMounted);
+        }
+
+        let mut dir = Dir::root();                       // Start at root
+
+        if pathname == "/" {                             // Special-case root
+            return Ok(dir);
+        }
+
+        // Walk each path component
+        for name in pathname.trim_start_matches('/').split('/') {
+            let de = dir.find(name)?;                    // Missing component
+            if !de.is_dir() {
+                return Err(FsError::NotADirectory);      // Not a dir
+            }
+            dir = de.to_dir();                           // Descend into subdir
+        }
+        Ok(dir)
+    }
+
+    // Get this directory's block address
+    pub fn addr(&self) -> u32 {
+        self.addr
+    }
+
+    // Find an entry by name in this directory, returning its metadata
+    pub fn find(&self, name: &str) -> Result<DirEntry, FsError> {
+        for entry in self.read()? {
+            if entry.name == name {
+                return Ok(entry);
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    // Create a new file entry in this directory
+    pub fn create_file(&self, name: &str) -> Result<DirEntry, FsError> {
+        self.create_entry(FileType::File, name)
+    }
+
+    // Create a new subdirectory entry in this directory
+    pub fn create_dir(&self, name: &str) -> Result<DirEntry, FsError> {
+        self.create_entry(FileType::Dir, name)
+    }
+
+    // Core routine to append a DirEntry (file or dir) to this directory
+    fn create_entry(&self, kind: FileType, name: &str) -> Result<DirEntry, FsError> {
+        journal::transaction(|| {
+            self.check_new_name(name)?;
+
+            // Allocate a fresh block to hold the file/dir's data
+            let entry_block = Block::alloc()?;
+            let entry_addr  = entry_block.addr();
+
+            let entry_size  = 0;                // newly created entries start with size 0
+            let entry_time  = now();
+            let times = (entry_time, entry_time, entry_time);
+            let entry = DirEntry::new(*self, kind, entry_addr, entry_size, times, name);
+            if let Err(e) = self.insert_entry(&entry) {
+                BlockBitmap::free(entry_addr)?;      // Don't leak the data block
+                return Err(e);
+            }
+
+            // Return a DirEntry wrapper for the new file/dir
+            Ok(entry)
+        })
+    }
+
+    // Check that `name` can be stored as a new entry of this directory
+    fn check_new_name(&self, name: &str) -> Result<(), FsError> {
+        // Reject names that cannot be stored or looked up again
+        if name.is_empty() || name.contains('/') {
+            return Err(FsError::InvalidPath);
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err(FsError::NameTooLong);
+        }
+
+        // Skip if name already exists
+        match self.find(name) {
+            Ok(_) => Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Write an entry in the first deleted slot of this directory where it fits,
+    // or else in the free space at the end of a block, chaining a new block to
+    // the directory when none has room for it. A slot larger than the entry
+    // keeps the rest as a deleted entry, so it is only taken when the entry
+    // fills it or leaves room for a header and a name after it.
+    fn insert_entry(&self, entry: &DirEntry) -> Result<(), FsError> {
+        let len = entry.len();
+        let mut block = Block::read(self.addr)?;
+        loop {
+            let (slots, end) = slots(block.data());
+            let slot = slots.iter()
+                .find(|&&(_, size, used)| !used && (size == len || size > len + DIR_ENTRY_HEADER))
+                .map(|&(i, size, _)| (i, size));
+            let free = if block.data().len() - end >= len { Some((end, len)) } else { None };
+            if let Some((i, size)) = slot.or(free) {
+                let data = block.data_mut();
+                entry.encode(data, i);
+                if size > len {
+                    // Rest of the slot: a deleted file entry with what is left of the old name
+                    data[i + len] = FileType::File as u8;
+                    write_u32(data, i + len + 1, 0);
+                    data[i + len + 33] = (size - len - DIR_ENTRY_HEADER) as u8;
+                }
+                return block.write();
+            }
+            match block.next()? {
+                Some(nb) => block = nb,
+                None => break,
+            }
+        }
+
+        // No block has room left for the new entry header+name: allocate a new one
+        let mut nb = Block::alloc()?;
+        block.set_next(nb.addr);
+        block.write()?;
+        entry.encode(nb.data_mut(), 0);
+        nb.write()
+    }
+
+    // Remove (delete) an entry by name: zero its addr and free all its blocks.
+    // Directories must be empty, otherwise the blocks of their children would leak.
+    // The blocks of a file with hard links are freed with its last name.
+    // The directory is compacted when that frees one of its blocks.
+    pub fn delete_entry(&mut self, name: &str) -> Result<(), FsError> {
+        journal::transaction(|| {
+            let entry = self.find(name)?;
+            if entry.is_dir() && entry.to_dir().read()?.next().is_some() {
+                return Err(FsError::NotEmpty);
+            }
+            self.remove_entry(name)?;
+            self.pack(false)?;
+            if let Some(addr) = entry.node() {
+                let mut node = Node::read(addr)?;
+                if node.links() > 1 {
+                    node.set_links(node.links() - 1);
+                    return node.write();
+                }
+                BlockBitmap::free(addr)?;
+            }
+
+            // Walk and free each chained block belonging to this entry
+            BlockBitmap::free_chain(entry.addr)
+        })
+    }
+
+    // Zero-out the stored block address of an entry to mark it deleted, leaving its blocks alone
+    pub fn remove_entry(&mut self, name: &str) -> Result<DirEntry, FsError> {
+        let mut rd = self.read()?;
+        for entry in &mut rd {
+            if entry.name == name {
+                let data = rd.block.data_mut();
+                let i = rd.data_offset - entry.len();
+                write_u32(data, i + 1, 0);
+                rd.block.write()?;
+                return Ok(entry);
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    // Rename an entry of this directory
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), FsError> {
+        let mut dir = *self;
+        self.move_entry(old, &mut dir, new)
+    }
+
+    // Move an entry of this directory to `dest` under a new name. Only directory
+    // entries are rewritten: the entry is written at the end of `dest` (where a
+    // longer name may need a new block) and then removed from here, so the data
+    // chain is never touched.
+    pub fn move_entry(&mut self, name: &str, dest: &mut Dir, new_name: &str) -> Result<(), FsError> {
+        journal::transaction(|| {
+            let mut entry = self.find(name)?;
+            if self.addr == dest.addr && name == new_name {
+                return Ok(());
+            }
+            dest.check_new_name(new_name)?;
+
+            entry.dir = *dest;
+            entry.name = new_name.to_owned();
+            dest.insert_entry(&entry)?;
+            self.remove_entry(name).map(|_| ())
+        })
+    }
+
+    // Point the entry of a file that gets a second name to its new node
+    fn set_node(&mut self, name: &str, node: u32) -> Result<(), FsError> {
+        let mut rd = self.read()?;
+        for mut entry in &mut rd {
+            if entry.name == name {
+                entry.node = node;
+                let data = rd.block.data_mut();
+                let i = rd.data_offset - entry.len();
+                data[i] = entry.kind_byte();
+                write_u32(data, i + 1, node);
+                return rd.block.write();
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    // Update the size, compression and times in the directory entry header after a read or write
+    fn update_entry(&mut self, name: &str, size: u32, compressed: bool, modified: u64, accessed: u64) -> Result<(), FsError> {
+        let mut rd = self.read()?;
+        for mut entry in &mut rd {
+            if entry.name == name {
+                entry.compressed = compressed;
+                let data = rd.block.data_mut();
+                let i = rd.data_offset - entry.len();
+                data[i] = entry.kind_byte();
+                write_u32(data, i + 5, size);
+                write_u64(data, i + 17, modified);
+                write_u64(data, i + 25, accessed);
+                return rd.block.write();
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    // Give an entry whose content cannot be read a new empty block, keeping
+    // its name, kind and times: a file gets size 0 and a directory no entries.
+    // The names of a file with hard links get it through their node.
+    pub(crate) fn clear_entry(&mut self, name: &str) -> Result<(), FsError> {
+        journal::transaction(|| {
+            let entry = self.find(name)?;
+            let block = Block::alloc()?;
+            if let Some(addr) = entry.node() {
+                let mut node = Node::read(addr)?;
+                let data = node.block.data_mut();
+                write_u32(data, 4, block.addr());
+                write_u32(data, 8, 0);
+                data[36] = 0;
+                return node.write();
+            }
+            let mut rd = self.read()?;
+            for mut entry in &mut rd {
+                if entry.name == name {
+                    entry.compressed = false;
+                    let data = rd.block.data_mut();
+                    let i = rd.data_offset - entry.len();
+                    data[i] = entry.kind_byte();
+                    write_u32(data, i + 1, block.addr());
+                    write_u32(data, i + 5, 0);
+                    return rd.block.write();
+                }
+            }
+            Err(FsError::NotFound)
+        })
+    }
+
+    // Begin iterating over entries in this directory
+    pub fn read(&self) -> Result<ReadDir, FsError> {
+        Ok(ReadDir {
+            dir: *self,
+            block: Block::read(self.addr)?,
+            data_offset: 0,
+        })
+    }
+
+    // Rewrite the chain of this directory without its deleted entries and free
+    // the blocks left empty at its end. Returns the number of blocks freed.
+    pub fn compact(&mut self) -> Result<usize, FsError> {
+        self.pack(true)
+    }
+
+    // Compact this directory, only when that frees a block unless `always` is set.
+    // The entries keep their order and the first block stays where it is, since
+    // the parent entry points to it.
+    fn pack(&mut self, always: bool) -> Result<usize, FsError> {
+        journal::transaction(|| {
+            // Collect the entries in use, and the blocks of the chain
+            let mut blocks = Vec::new();
+            let mut entries: Vec<Vec<u8>> = Vec::new();
+            let mut deleted = false;
+            let mut block = Block::read(self.addr)?;
+            loop {
+                for (i, len, used) in slots(block.data()).0 {
+                    if used {
+                        entries.push(block.data()[i..i + len].to_vec());
+                    } else {
+                        deleted = true;
+                    }
+                }
+                let next = block.next()?;
+                blocks.push(block);
+                match next {
+                    Some(nb) => block = nb,
+                    None => break,
+                }
+            }
+
+            // Lay them out back to back, starting a block when one is full
+            let mut layout: Vec<Vec<u8>> = vec![Vec::new()];
+            for entry in entries {
+                if layout[layout.len() - 1].len() + entry.len() > DATA_SIZE {
+                    layout.push(Vec::new());
+                }
+                let last = layout.len() - 1;
+                layout[last].extend_from_slice(&entry);
+            }
+            let freed = blocks.len() - layout.len();
+            if freed == 0 && !(always && deleted) {
+                return Ok(0);
+            }
+
+            let used = layout.len();
+            for (block, bytes) in blocks.iter_mut().zip(&layout) {
+                let data = block.data_mut();
+                data.iter_mut().for_each(|b| *b = 0);
+                data[..bytes.len()].copy_from_slice(bytes);
+            }
+            blocks[used - 1].set_next(0);
+            for block in &blocks[..used] {
+                block.write()?;
+            }
+            for block in &blocks[used..] {
+                BlockBitmap::free(block.addr())?;
+            }
+            Ok(freed)
+        })
+    }
+
+    // Convenience: delete by full pathname
+    pub fn delete(pathname: &str) -> Result<(), FsError> {
+        let pathname = resolve(pathname, false)?;
+        if pathname == "/" {
+            return Err(FsError::InvalidPath);
+        }
+        let dirname  = dirname(&pathname);
+        let filename = filename(&pathname);
+        let mut dir = Dir::walk(dirname)?;
+        if !dir.find(filename)?.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        dir.delete_entry(filename)
+    }
+
+    // Delete a file or a directory with everything below it, by full pathname;
+    // symbolic links are deleted, not followed
+    pub fn delete_all(pathname: &str) -> Result<(), FsError> {
+        journal::transaction(|| {
+            let pathname = resolve(pathname, false)?;
+            if pathname == "/" {
+                return Err(FsError::InvalidPath);
+            }
+            let mut dir = Dir::walk(dirname(&pathname))?;
+            dir.delete_entry_all(filename(&pathname))
+        })
+    }
+
+    // Delete an entry depth-first: the children of a directory are deleted
+    // before the directory itself, so each chain is freed exactly once
+    fn delete_entry_all(&mut self, name: &str) -> Result<(), FsError> {
+        let entry = self.find(name)?;
+        if entry.is_dir() {
+            let mut dir = entry.to_dir();
+            // Collect names first: the entries are rewritten while deleting
+            let names: Vec<String> = dir.read()?.map(|child| child.name()).collect();
+            for name in names {
+                dir.delete_entry_all(&name)?;
+            }
+        }
+        self.delete_entry(name)
+    }
+}
+
+// Iterator over directory entries (stops early if the next block cannot be read)
+pub struct ReadDir {
+    dir: Dir,             // Directory being iterated
+    block: Block,         // Current block buffer
+    data_offset: usize,   // Offset within block.data()
+}
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<DirEntry> {
+        loop {
+            let data = self.block.data();
+            let mut i = self.data_offset;
+
+            // Scan for next valid entry in this block
+            loop {
+                if i >= data.len() - DIR_ENTRY_HEADER {
+                    break; // Not enough space for another entry header
+                }
+
+                // Parse entry header
+                let flags = data[i];
+                let kind = match flags & !(COMPRESSED | HARD_LINKED) {
+                    0 => FileType::Dir,
+                    1 => FileType::File,
+                    2 => FileType::Symlink,
+                    _ => break,
+                };
+                let compressed = flags & COMPRESSED != 0;
+                let addr = read_u32(data, i + 1);
+                let size = read_u32(data, i + 5);
+                let times = (read_u64(data, i + 9), read_u64(data, i + 17), read_u64(data, i + 25));
+                i += 33;
+
+                // Read name length
+                let mut n = data[i];
+                if n == 0 || n as usize > data.len() - i {
+                    break;
+                }
+                i += 1;
+
+                // Read the name characters
+                let mut name = String::new();
+                while n > 0 {
+                    name.push(data[i] as char);
+                    i += 1;
+                    n -= 1;
+                }
+
+                self.data_offset = i;
+
+                // Skip entries marked deleted (addr == 0)
+                if addr == 0 {
+                    continue;
+                }
+
+                // Return the DirEntry
+                let mut entry = DirEntry::new(self.dir, kind, addr, size, times, &name);
+                entry.compressed = compressed;
+                // A node that cannot be read leaves the entry without content
+                // (addr 0), for fsck to find
+                if flags & HARD_LINKED != 0 {
+                    entry.node = addr;
+                    entry.addr = 0;
+                    entry.size = 0;
+                    if let Ok(node) = Node::read(addr) {
+                        node.load(&mut entry);
+                    }
+                }
+                return Some(entry);
+            }
+
+            // Move to next block in chain
+            if let Ok(Some(nb)) = self.block.next() {
+                self.block = nb;
+                self.data_offset = 0;
+            } else {
+                break;
+            }
+        }
+        None
+    }
+}
+
+// Metadata of a file with hard links. Its names share it, so it moves out of
+// their directory entries into a block of its own, which they point to:
+// links(4) | addr(4) | size(4) | created(8) | modified(8) | accessed(8) | compressed(1)
+// where links is the number of names and addr the first block of the content.
+pub struct Node {
+    block: Block,
+}
+
+impl Node {
+    pub fn read(addr: u32) -> Result<Self, FsError> {
+        Ok(Self { block: Block::read(addr)? })
+    }
+
+    // Give the file of an entry a node, with one name for now
+    fn create(entry: &DirEntry) -> Result<Self, FsError> {
+        let mut node = Self { block: Block::alloc()? };
+        let data = node.block.data_mut();
+        write_u32(data, 0, 1);
+        write_u32(data, 4, entry.addr);
+        write_u32(data, 8, entry.size);
+        write_u64(data, 12, entry.created);
+        write_u64(data, 20, entry.modified);
+        write_u64(data, 28, entry.accessed);
+        data[36] = entry.compressed as u8;
+        node.write()?;
+        Ok(node)
+    }
+
+    pub fn addr(&self) -> u32 { self.block.addr() }
+    pub fn links(&self) -> u32 { read_u32(self.block.data(), 0) }
+
+    pub fn set_links(&mut self, links: u32) {
+        write_u32(self.block.data_mut(), 0, links);
+    }
+
+    pub fn write(&self) -> Result<(), FsError> {
+        self.block.write()
+    }
+
+    // Fill an entry that points to this node
+    fn load(&self, entry: &mut DirEntry) {
+        let data = self.block.data();
+        entry.links = read_u32(data, 0);
+        entry.addr = read_u32(data, 4);
+        entry.size = read_u32(data, 8);
+        entry.created = read_u64(data, 12);
+        entry.modified = read_u64(data, 20);
+        entry.accessed = read_u64(data, 28);
+        entry.compressed = data[36] != 0;
+    }
+
+    // Keep what a write changed in a file
+    fn save(&mut self, file: &File) {
+        let data = self.block.data_mut();
+        write_u32(data, 8, file.size);
+        write_u64(data, 20, file.modified);
+        write_u64(data, 28, file.accessed);
+        data[36] = file.compressed as u8;
+    }
+}
+
+// What the file system needs from the system it runs on, besides its disk: the
+// kernel passes the RTC and the shell process, the host tool the system clock
+#[derive(Clone, Copy)]
+pub struct Platform {
+    pub realtime: fn() -> u64, // current time in Unix seconds
+    pub dir: fn() -> String,   // current directory, for relative paths
+}
+
+// Register the platform; without one, times are 0 and paths are relative to the root
+pub fn set_platform(platform: Platform) {
+    *PLATFORM.lock() = Some(platform);
+}
+
+fn platform() -> Option<Platform> {
+    *PLATFORM.lock()
+}
+
+// Check whether a filesystem has been mounted (block device set)
+pub fn is_mounted() -> bool {
+    volume().block_device.lock().is_some()
+}
+
+// Check whether the mounted device refuses writes
+pub fn is_read_only() -> bool {
+    volume().block_device.lock().as_ref().is_some_and(|d| d.is_read_only())
+}
+
+// Mount a filesystem by reading its superblock and giving its device to the current volume.
+// Fails with Busy when the current volume is already mounted, whose cache and journal would be lost.
+pub fn mount(mut block_device: Box<dyn BlockDevice>) -> Result<(), FsError> {
+    if is_mounted() {
+        return Err(FsError::Busy);
+    }
+    let sb = Superblock::read(&mut *block_device)?;
+    if !sb.is_supported() || sb.block_count() > block_device.block_count() {
+        return Err(FsError::Unsupported);
+    }
+    if sb.is_encrypted() && !block_device.is_encrypted() {
+        return Err(FsError::Locked);
+    }
+    let volume = volume();
+    *volume.superblock.lock() = Some(sb);
+    *volume.block_device.lock() = Some(block_device);
+    cache::invalidate();
+
+    // Finish the last transaction if it was committed before a crash
+    if let Err(e) = journal::replay().and_then(|_| BlockBitmap::load()) {
+        *volume.superblock.lock() = None;
+        *volume.block_device.lock() = None;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Give the device of an encrypted volume the key derived from `passphrase`, so
+// that it can be mounted; the device of a clear volume is returned as it is
+pub fn unlock(mut block_device: Box<dyn BlockDevice>, passphrase: &str) -> Result<Box<dyn BlockDevice>, FsError> {
+    let enc = match Superblock::read(&mut *block_device)?.encryption() {
+        Some(enc) => enc,
+        None => return Ok(block_device),
+    };
+    let (key, check) = crypt::derive_key(passphrase, &enc.salt, enc.iterations);
+    if !crypt::same_check(&check, &enc.check) {
+        return Err(FsError::BadPassphrase);
+    }
+    Ok(Box::new(EncryptedDevice::new(block_device, key)))
+}
+
+// Format a disk: write a superblock sized from the disk, an empty journal, a
+// bitmap where only the root directory block is allocated, then mount it
+pub fn format(block_device: Box<dyn BlockDevice>) -> Result<(), FsError> {
+    format_volume(block_device, None)
+}
+
+// Format a disk like `format`, encrypting the volume with a key derived from
+// `passphrase` and `salt`, which should be random
+pub fn format_encrypted(block_device: Box<dyn BlockDevice>, passphrase: &str, salt: [u8; 16]) -> Result<(), FsError> {
+    format_volume(block_device, Some((passphrase, salt)))
+}
+
+fn format_volume(mut block_device: Box<dyn BlockDevice>, passphrase: Option<(&str, [u8; 16])>) -> Result<(), FsError> {
+    if is_mounted() {
+        return Err(FsError::Busy);
+    }
+    let mut sb = Superblock::new(block_device.block_count());
+    if !sb.is_supported() {
+        return Err(FsError::NoSpace); // disk too small to hold a volume
+    }
+    if let Some((passphrase, salt)) = passphrase {
+        let iterations = crypt::KDF_ITERATIONS;
+        let (key, check) = crypt::derive_key(passphrase, &salt, iterations);
+        sb.encryption = Some(Encryption { iterations, salt, check });
+        block_device = Box::new(EncryptedDevice::new(block_device, key));
+    }
+    sb.write(&mut *block_device)?;
+
+    // Zero the journal first, so that mounting does not replay a transaction of a previous volume
+    for addr in sb.journal_addr()..sb.bitmap_addr() {
+        block_device.write_block(addr, &[0; 512])?;
+    }
+
+    // Zero the bitmap and the root dir, which may contain data of a previous
+    // volume, with valid checksums, and mark the root dir block as allocated
+    for addr in sb.bitmap_addr()..sb.data_addr() {
+        let mut block = Block::new(addr);
+        if addr == BlockBitmap::block_index(&sb, sb.root_addr()) {
+            let i = BlockBitmap::buffer_index(&sb, sb.root_addr());
+            block.data_mut()[i / 8].set_bit(i % 8, true);
+        }
+        block_device.write_block(addr, &block.encode())?;
+    }
+    block_device.write_block(sb.root_addr(), &Block::new(sb.root_addr()).encode())?;
+    mount(block_device)?;
+    sync()
+}
+
+// Write the blocks held in the cache to the disk
+pub fn sync() -> Result<(), FsError> {
+    journal::checkpoint()?;
+    volume().device(|block_device| block_device.flush())
+}
+
+// Sync and unmount the volume, giving its device back
+pub fn unmount() -> Result<Box<dyn BlockDevice>, FsError> {
+    sync()?;
+    let volume = volume();
+    *volume.superblock.lock() = None;
+    cache::invalidate();
+    let block_device = volume.block_device.lock().take();
+    block_device.ok_or(FsError::NotMounted)
+}
+
+// Everything the functions of this module know about a volume: each `Volume`
+// has its own, so that two volumes never share a device, a cache or a journal
+pub(crate) struct VolumeState {
+    block_device: Mutex<Option<Box<dyn BlockDevice>>>,
+    superblock: Mutex<Option<Superblock>>,
+    free_space: Mutex<FreeSpace>,
+    pub(crate) cache: Mutex<cache::Cache>,
+    pub(crate) journal: Mutex<journal::Journal>,
+}
+
+impl VolumeState {
+    fn new() -> Self {
+        Self {
+            block_device: Mutex::new(None),
+            superblock: Mutex::new(None),
+            free_space: Mutex::new(FreeSpace { count: 0, hint: 0 }),
+            cache: Mutex::new(cache::Cache::new()),
+            journal: Mutex::new(journal::Journal::new()),
+        }
+    }
+
+    // Run `f` on the device of the volume
+    pub(crate) fn device<T>(&self, f: impl FnOnce(&mut dyn BlockDevice) -> Result<T, FsError>) -> Result<T, FsError> {
+        match *self.block_device.lock() {
+            Some(ref mut block_device) => f(&mut **block_device),
+            None => Err(FsError::NotMounted),
+        }
+    }
+}
+
+// The volume the functions of this module work on
+pub(crate) fn volume() -> Arc<VolumeState> {
+    match CURRENT.lock().last() {
+        Some(state) => state.clone(),
+        None => DEFAULT_VOLUME.clone(),
+    }
+}
+
+// Makes the volume given to `Volume::run` current until it is dropped, even
+// when the call panics
+struct Current;
+
+impl Current {
+    fn enter(state: &Arc<VolumeState>) -> Self {
+        CURRENT.lock().push(state.clone());
+        Current
+    }
+}
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        CURRENT.lock().pop();
+    }
+}
+
+// A mounted volume of its own, so that several volumes can be mounted at once.
+// The functions of this module are not given a volume: they work on the
+// default one, where `mount` and `format` put a device, or on the one whose
+// `run` is in progress. Calls to `run` can be nested, on the same volume or
+// on another one, and each returns to the volume that was current before it.
+pub struct Volume {
+    state: Arc<VolumeState>,
+}
+
+impl Volume {
+    // Mount a device as a volume of its own
+    pub fn mount(block_device: Box<dyn BlockDevice>) -> Result<Self, FsError> {
+        Self::aside(|| mount(block_device))
+    }
+
+    // Format a device as a volume of its own
+    pub fn format(block_device: Box<dyn BlockDevice>) -> Result<Self, FsError> {
+        Self::aside(|| format(block_device))
+    }
+
+    // Format a device as an encrypted volume of its own
+    pub fn format_encrypted(block_device: Box<dyn BlockDevice>, passphrase: &str, salt: [u8; 16]) -> Result<Self, FsError> {
+        Self::aside(|| format_encrypted(block_device, passphrase, salt))
+    }
+
+    fn aside(f: impl FnOnce() -> Result<(), FsError>) -> Result<Self, FsError> {
+        let volume = Self { state: Arc::new(VolumeState::new()) };
+        volume.run(f)?;
+        Ok(volume)
+    }
+
+    // Run `f` on this volume
+    pub fn run<T, F: FnOnce() -> Result<T, FsError>>(&self, f: F) -> Result<T, FsError> {
+        let _current = Current::enter(&self.state);
+        f()
+    }
+}
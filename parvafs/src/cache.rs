@@ -0,0 +1,165 @@
+// LRU write-back cache of ParvaFS blocks, between the journal and the BlockDevice
+//
+// Written blocks stay dirty in the cache until they are evicted or `sync` is
+// called. This is safe because the journal writes every staged block to disk
+// before handing it to the cache, and replays it on mount after a crash; the
+// blocks a transaction writes in place are flushed before it is committed.
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use super::ParvaFS::{volume, FsError};
+
+// Number of blocks kept in memory by default (64 KiB)
+pub const DEFAULT_CAPACITY: usize = 128;
+
+struct Entry {
+    addr: u32,
+    buf: [u8; 512],
+    dirty: bool,
+    used: u64, // value of the clock at the last access
+}
+
+// Each volume has its own cache, kept with its device
+pub(crate) struct Cache {
+    capacity: usize,
+    entries: Vec<Entry>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// Counters reported by `stats`
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub len: usize,
+    pub dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+fn device_read(addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+    volume().device(|block_device| block_device.read_block(addr, buf))
+}
+
+fn device_write(addr: u32, buf: &[u8]) -> Result<(), FsError> {
+    volume().device(|block_device| block_device.write_block(addr, buf))
+}
+
+impl Cache {
+    pub(crate) fn new() -> Self {
+        Cache {
+            capacity: DEFAULT_CAPACITY,
+            entries: Vec::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn find(&mut self, addr: u32) -> Option<&mut Entry> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.iter_mut().find(|e| e.addr == addr)?;
+        entry.used = clock;
+        Some(entry)
+    }
+
+    // Drop least recently used blocks until `len` are left, writing back the dirty ones
+    fn shrink(&mut self, len: usize) -> Result<(), FsError> {
+        while self.entries.len() > len {
+            let (i, _) = self.entries.iter().enumerate().min_by_key(|(_, e)| e.used).unwrap();
+            if self.entries[i].dirty {
+                device_write(self.entries[i].addr, &self.entries[i].buf)?;
+            }
+            self.entries.swap_remove(i);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, addr: u32, buf: &[u8; 512], dirty: bool) -> Result<(), FsError> {
+        if self.capacity == 0 {
+            return if dirty { device_write(addr, buf) } else { Ok(()) };
+        }
+        self.shrink(self.capacity - 1)?;
+        let used = self.clock;
+        self.entries.push(Entry { addr, buf: *buf, dirty, used });
+        Ok(())
+    }
+}
+
+// Read a block, from memory if it is cached
+pub fn read(addr: u32, buf: &mut [u8; 512]) -> Result<(), FsError> {
+    let volume = volume();
+    let mut cache = volume.cache.lock();
+    if let Some(entry) = cache.find(addr) {
+        *buf = entry.buf;
+        cache.hits += 1;
+        return Ok(());
+    }
+    cache.misses += 1;
+    device_read(addr, buf)?;
+    cache.insert(addr, buf, false)
+}
+
+// Write a block to memory; it reaches the disk when evicted or on `sync`
+pub fn write(addr: u32, buf: &[u8; 512]) -> Result<(), FsError> {
+    let volume = volume();
+    let mut cache = volume.cache.lock();
+    if let Some(entry) = cache.find(addr) {
+        entry.buf = *buf;
+        entry.dirty = true;
+        return Ok(());
+    }
+    cache.insert(addr, buf, true)
+}
+
+// Write every dirty block to the disk, in address order
+pub fn sync() -> Result<(), FsError> {
+    let volume = volume();
+    let mut cache = volume.cache.lock();
+    cache.entries.sort_unstable_by_key(|e| e.addr);
+    for entry in cache.entries.iter_mut().filter(|e| e.dirty) {
+        device_write(entry.addr, &entry.buf)?;
+        entry.dirty = false;
+    }
+    Ok(())
+}
+
+// Write the dirty blocks among `addrs` to the disk now
+pub fn flush(addrs: &BTreeSet<u32>) -> Result<(), FsError> {
+    let volume = volume();
+    let mut cache = volume.cache.lock();
+    for entry in cache.entries.iter_mut().filter(|e| e.dirty && addrs.contains(&e.addr)) {
+        device_write(entry.addr, &entry.buf)?;
+        entry.dirty = false;
+    }
+    Ok(())
+}
+
+// Forget every block without writing it, when the volume gets or gives back its device
+pub fn invalidate() {
+    volume().cache.lock().entries.clear();
+}
+
+// Change the number of cached blocks (0 disables the cache)
+pub fn set_capacity(capacity: usize) -> Result<(), FsError> {
+    let volume = volume();
+    let mut cache = volume.cache.lock();
+    cache.shrink(capacity)?;
+    cache.capacity = capacity;
+    Ok(())
+}
+
+pub fn stats() -> CacheStats {
+    let volume = volume();
+    let cache = volume.cache.lock();
+    CacheStats {
+        capacity: cache.capacity,
+        len: cache.entries.len(),
+        dirty: cache.entries.iter().filter(|e| e.dirty).count(),
+        hits: cache.hits,
+        misses: cache.misses,
+    }
+}
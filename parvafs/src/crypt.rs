@@ -0,0 +1,411 @@
+// Volume encryption: a key derived from a passphrase, and a block device
+// that encrypts the blocks of the volume with it
+//
+// The key comes from PBKDF2-HMAC-SHA256 over the passphrase and a random salt
+// kept in the superblock, with as many iterations as the superblock says.
+// The 16 bytes after the key are a check value, also kept in the superblock,
+// that tells a wrong passphrase apart without decrypting anything.
+//
+// Each block is encrypted with XTS-AES-128, the disk encryption mode of IEEE
+// 1619, with its address as the tweak. The superblock, and the reserved region
+// before it, stay in clear. XTS keeps the block size, so the layout is the
+// same as on a clear volume, and unlike a stream cipher it does not reuse a
+// key stream: each 16 bytes of a block go through AES, so two versions of a
+// block, or a journal slot and the block it holds, tell nothing about each
+// other beyond which 16-byte pieces are equal.
+//
+// An attacker who sees several versions of the image still learns which
+// pieces of a block changed, and there is no integrity beyond the CRC32 of
+// each block.
+
+use alloc::boxed::Box;
+
+use super::device::BlockDevice;
+use super::ParvaFS::{FsError, SUPERBLOCK_ADDR};
+
+// Iterations of PBKDF2 for new volumes
+pub const KDF_ITERATIONS: u32 = 20_000;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// SHA-256 of data given in one or more parts
+#[derive(Clone)]
+struct Sha256 {
+    state: [u32; 8],
+    buf: [u8; 64],
+    len: usize, // bytes in `buf`
+    total: u64, // bytes given so far
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ],
+            buf: [0; 64],
+            len: 0,
+            total: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+        while !data.is_empty() {
+            let n = (64 - self.len).min(data.len());
+            self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+            if self.len == 64 {
+                self.compress();
+                self.len = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.total * 8;
+        self.update(&[0x80]);
+        while self.len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut hash = [0; 32];
+        for (i, word) in self.state.iter().enumerate() {
+            hash[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (word, b) in w.iter_mut().zip(self.buf.chunks(4)) {
+            *word = u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let mut s = self.state;
+        for i in 0..64 {
+            let s1 = s[4].rotate_right(6) ^ s[4].rotate_right(11) ^ s[4].rotate_right(25);
+            let ch = (s[4] & s[5]) ^ (!s[4] & s[6]);
+            let t1 = s[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = s[0].rotate_right(2) ^ s[0].rotate_right(13) ^ s[0].rotate_right(22);
+            let maj = (s[0] & s[1]) ^ (s[0] & s[2]) ^ (s[1] & s[2]);
+            let t2 = s0.wrapping_add(maj);
+            s = [t1.wrapping_add(t2), s[0], s[1], s[2], s[3].wrapping_add(t1), s[4], s[5], s[6]];
+        }
+        for (state, v) in self.state.iter_mut().zip(s.iter()) {
+            *state = state.wrapping_add(*v);
+        }
+    }
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
+
+// HMAC-SHA256, with the padded key hashed once for every message
+struct Hmac {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl Hmac {
+    fn new(key: &[u8]) -> Self {
+        let mut block = [0u8; 64];
+        if key.len() > 64 {
+            block[..32].copy_from_slice(&sha256(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut ipad = [0x36u8; 64];
+        let mut opad = [0x5cu8; 64];
+        for i in 0..64 {
+            ipad[i] ^= block[i];
+            opad[i] ^= block[i];
+        }
+        let mut inner = Sha256::new();
+        let mut outer = Sha256::new();
+        inner.update(&ipad);
+        outer.update(&opad);
+        Self { inner, outer }
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut inner = self.inner.clone();
+        for part in parts {
+            inner.update(part);
+        }
+        let mut outer = self.outer.clone();
+        outer.update(&inner.finish());
+        outer.finish()
+    }
+}
+
+// Fill `out` with PBKDF2-HMAC-SHA256 of a password
+pub fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let hmac = Hmac::new(password);
+    for (i, chunk) in out.chunks_mut(32).enumerate() {
+        let mut u = hmac.mac(&[salt, &(i as u32 + 1).to_be_bytes()]);
+        let mut t = u;
+        for _ in 1..iterations {
+            u = hmac.mac(&[&u]);
+            for (t, u) in t.iter_mut().zip(u.iter()) {
+                *t ^= u;
+            }
+        }
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
+
+// The key of a volume and its check value
+pub fn derive_key(passphrase: &str, salt: &[u8; 16], iterations: u32) -> ([u8; 32], [u8; 16]) {
+    let mut out = [0u8; 64];
+    pbkdf2(passphrase.as_bytes(), salt, iterations, &mut out);
+    let mut key = [0; 32];
+    let mut check = [0; 16];
+    key.copy_from_slice(&out[..32]);
+    check.copy_from_slice(&out[32..48]);
+    out.iter_mut().for_each(|b| *b = 0);
+    (key, check)
+}
+
+// Multiplication in GF(2^8) with the AES polynomial x^8 + x^4 + x^3 + x + 1
+const fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    p
+}
+
+// AES S-box and its inverse, computed at compile time: the multiplicative
+// inverse (x^254) followed by the affine transform
+const SBOX: [u8; 256] = sbox();
+const INV_SBOX: [u8; 256] = inv_sbox();
+
+const fn sbox() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut x = 0;
+    while x < 256 {
+        let mut inv = 1u8;
+        let mut i = 0;
+        while i < 254 {
+            inv = gmul(inv, x as u8);
+            i += 1;
+        }
+        table[x] = inv ^ inv.rotate_left(1) ^ inv.rotate_left(2) ^ inv.rotate_left(3) ^ inv.rotate_left(4) ^ 0x63;
+        x += 1;
+    }
+    table
+}
+
+const fn inv_sbox() -> [u8; 256] {
+    let sbox = sbox();
+    let mut table = [0; 256];
+    let mut x = 0;
+    while x < 256 {
+        table[sbox[x] as usize] = x as u8;
+        x += 1;
+    }
+    table
+}
+
+// AES-128 (FIPS 197) with its expanded key; the state is the 16 bytes of a
+// block, column by column
+struct Aes128 {
+    round_keys: [[u8; 16]; 11],
+}
+
+impl Aes128 {
+    fn new(key: &[u8]) -> Self {
+        let mut round_keys = [[0; 16]; 11];
+        round_keys[0].copy_from_slice(&key[..16]);
+        let mut rcon = 1;
+        for r in 1..11 {
+            let prev = round_keys[r - 1];
+            let mut word = [SBOX[prev[13] as usize] ^ rcon, SBOX[prev[14] as usize], SBOX[prev[15] as usize], SBOX[prev[12] as usize]];
+            for i in 0..16 {
+                word[i % 4] ^= prev[i];
+                round_keys[r][i] = word[i % 4];
+            }
+            rcon = gmul(rcon, 2);
+        }
+        Self { round_keys }
+    }
+
+    fn add_round_key(&self, block: &mut [u8; 16], r: usize) {
+        for (b, k) in block.iter_mut().zip(self.round_keys[r].iter()) {
+            *b ^= k;
+        }
+    }
+
+    fn encrypt(&self, block: &mut [u8; 16]) {
+        self.add_round_key(block, 0);
+        for r in 1..11 {
+            // SubBytes and ShiftRows: row i of column c comes from column c + i
+            let s = *block;
+            for c in 0..4 {
+                for i in 0..4 {
+                    block[4 * c + i] = SBOX[s[4 * ((c + i) % 4) + i] as usize];
+                }
+            }
+            if r < 10 {
+                for col in block.chunks_mut(4) {
+                    let [a, b, c, d] = [col[0], col[1], col[2], col[3]];
+                    col[0] = gmul(a, 2) ^ gmul(b, 3) ^ c ^ d;
+                    col[1] = a ^ gmul(b, 2) ^ gmul(c, 3) ^ d;
+                    col[2] = a ^ b ^ gmul(c, 2) ^ gmul(d, 3);
+                    col[3] = gmul(a, 3) ^ b ^ c ^ gmul(d, 2);
+                }
+            }
+            self.add_round_key(block, r);
+        }
+    }
+
+    fn decrypt(&self, block: &mut [u8; 16]) {
+        self.add_round_key(block, 10);
+        for r in (0..10).rev() {
+            let s = *block;
+            for c in 0..4 {
+                for i in 0..4 {
+                    block[4 * ((c + i) % 4) + i] = INV_SBOX[s[4 * c + i] as usize];
+                }
+            }
+            self.add_round_key(block, r);
+            if r > 0 {
+                for col in block.chunks_mut(4) {
+                    let [a, b, c, d] = [col[0], col[1], col[2], col[3]];
+                    col[0] = gmul(a, 14) ^ gmul(b, 11) ^ gmul(c, 13) ^ gmul(d, 9);
+                    col[1] = gmul(a, 9) ^ gmul(b, 14) ^ gmul(c, 11) ^ gmul(d, 13);
+                    col[2] = gmul(a, 13) ^ gmul(b, 9) ^ gmul(c, 14) ^ gmul(d, 11);
+                    col[3] = gmul(a, 11) ^ gmul(b, 13) ^ gmul(c, 9) ^ gmul(d, 14);
+                }
+            }
+        }
+    }
+}
+
+// Do not leave the key in freed memory
+impl Drop for Aes128 {
+    fn drop(&mut self) {
+        for byte in self.round_keys.iter_mut().flatten() {
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+// XTS-AES-128 (IEEE 1619) of a whole block: the first half of the 32-byte key
+// encrypts the data, the second half the tweak, which is `unit` in little
+// endian. `buf` holds a multiple of 16 bytes.
+pub struct Xts {
+    data: Aes128,
+    tweak: Aes128,
+}
+
+impl Xts {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self { data: Aes128::new(&key[..16]), tweak: Aes128::new(&key[16..]) }
+    }
+
+    pub fn encrypt(&self, unit: u64, buf: &mut [u8]) {
+        self.crypt(unit, buf, Aes128::encrypt);
+    }
+
+    pub fn decrypt(&self, unit: u64, buf: &mut [u8]) {
+        self.crypt(unit, buf, Aes128::decrypt);
+    }
+
+    fn crypt(&self, unit: u64, buf: &mut [u8], cipher: fn(&Aes128, &mut [u8; 16])) {
+        let mut t = [0; 16];
+        t[..8].copy_from_slice(&unit.to_le_bytes());
+        self.tweak.encrypt(&mut t);
+        for chunk in buf.chunks_exact_mut(16) {
+            let mut block = [0; 16];
+            for i in 0..16 {
+                block[i] = chunk[i] ^ t[i];
+            }
+            cipher(&self.data, &mut block);
+            for i in 0..16 {
+                chunk[i] = block[i] ^ t[i];
+            }
+            // Next tweak: multiply by x in GF(2^128)
+            let carry = t[15] >> 7;
+            for i in (1..16).rev() {
+                t[i] = (t[i] << 1) | (t[i - 1] >> 7);
+            }
+            t[0] = (t[0] << 1) ^ (carry * 0x87);
+        }
+    }
+}
+
+// Compare two check values in a time that does not depend on where they differ
+pub fn same_check(a: &[u8; 16], b: &[u8; 16]) -> bool {
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// A device whose blocks after the superblock are encrypted
+pub struct EncryptedDevice {
+    inner: Box<dyn BlockDevice>,
+    xts: Xts,
+}
+
+impl EncryptedDevice {
+    pub fn new(inner: Box<dyn BlockDevice>, mut key: [u8; 32]) -> Self {
+        let xts = Xts::new(&key);
+        key.iter_mut().for_each(|b| *b = 0);
+        Self { inner, xts }
+    }
+}
+
+impl BlockDevice for EncryptedDevice {
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        self.inner.read_block(addr, buf)?;
+        if addr > SUPERBLOCK_ADDR {
+            self.xts.decrypt(addr as u64, buf);
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError> {
+        let mut copy = buf.to_vec();
+        if addr > SUPERBLOCK_ADDR {
+            self.xts.encrypt(addr as u64, &mut copy);
+        }
+        self.inner.write_block(addr, &copy)
+    }
+
+    fn block_count(&self) -> u32 {
+        self.inner.block_count()
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.inner.flush()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
@@ -0,0 +1,77 @@
+// Block devices: the disks ParvaFS can be formatted and mounted on
+//
+// The kernel implements `BlockDevice` for its ATA drives and the host tool for
+// image files; `MemDevice` keeps the whole disk in memory.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::ParvaFS::{FsError, BLOCK_SIZE};
+
+// A disk addressed in blocks of BLOCK_SIZE bytes
+pub trait BlockDevice: Send {
+    // Read block `addr` into `buf`
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError>;
+
+    // Write `buf` to block `addr`
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError>;
+
+    // Size of the disk in blocks
+    fn block_count(&self) -> u32;
+
+    // Make every block written so far durable, for devices that buffer writes
+    fn flush(&mut self) -> Result<(), FsError>;
+
+    // Whether the device decrypts the blocks of an encrypted volume (see `crypt`)
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    // Whether every write fails; the file system then skips the writes a
+    // read would make, like the access time
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+// A device backed by a Vec<u8>, for RAM disks and tests
+pub struct MemDevice {
+    data: Vec<u8>,
+}
+
+impl MemDevice {
+    // Create a zeroed device of `block_count` blocks
+    pub fn new(block_count: u32) -> Self {
+        Self { data: vec![0; block_count as usize * BLOCK_SIZE as usize] }
+    }
+
+    fn range(&self, addr: u32, len: usize) -> Result<core::ops::Range<usize>, FsError> {
+        let start = addr as usize * BLOCK_SIZE as usize;
+        if len != BLOCK_SIZE as usize || start + len > self.data.len() {
+            return Err(FsError::Io);
+        }
+        Ok(start..start + len)
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        let range = self.range(addr, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError> {
+        let range = self.range(addr, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        (self.data.len() / BLOCK_SIZE as usize) as u32
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
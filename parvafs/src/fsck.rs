@@ -0,0 +1,382 @@
+// fsck: consistency checker for ParvaFS, with an optional repair mode
+//
+// The tree is walked from the root directory and every block reachable through
+// `Block::next` is recorded with the chain that owns it. The result is then
+// compared against `BlockBitmap`. The names of a file with hard links are
+// counted and compared with the link count of its node. `scrub` only verifies
+// the checksum of every block in use.
+
+use alloc::collections::BTreeMap;
+use alloc::{format, string::String, vec, vec::Vec};
+use core::fmt;
+
+use super::cache;
+use super::ParvaFS::{self, Block, BlockBitmap, Dir, DirEntry, FsError, Node, Superblock, DATA_SIZE};
+
+// Directory where repair moves the entries whose chain had to be cut
+pub const LOST_AND_FOUND: &str = "/lost+found";
+
+// An inconsistency found on the volume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Leaked(u32),                          // allocated in the bitmap but not reachable
+    Unmarked(u32),                        // reachable but free in the bitmap
+    ClaimedTwice(String, u32),            // the chain of a path runs into a block owned by another entry
+    Dangling(String, u32),                // the entry or its chain points outside the data region
+    Loop(String, u32),                    // the chain points back to one of its own blocks
+    SizeExceedsChain(String, u32, usize), // recorded size is larger than what the chain can hold
+    Unreadable(String, u32),              // a block of the chain has a bad checksum or cannot be read
+    LinkCount(String, u32, u32),          // the node of a file counts other names than found
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Leaked(addr) => write!(f, "block {} is allocated but unused", addr),
+            Problem::Unmarked(addr) => write!(f, "block {} is used but marked free", addr),
+            Problem::ClaimedTwice(path, addr) => write!(f, "{}: block {} is claimed twice", path, addr),
+            Problem::Dangling(path, addr) => write!(f, "{}: invalid block address {}", path, addr),
+            Problem::Loop(path, addr) => write!(f, "{}: loop in chain at block {}", path, addr),
+            Problem::SizeExceedsChain(path, size, capacity) => {
+                write!(f, "{}: size {} exceeds chain capacity {}", path, size, capacity)
+            }
+            Problem::Unreadable(path, addr) => write!(f, "{}: block {} is corrupted or unreadable", path, addr),
+            Problem::LinkCount(path, links, found) => write!(f, "{}: link count {} but {} names", path, links, found),
+        }
+    }
+}
+
+// Result of a check
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub dirs: usize,
+    pub files: usize,
+    pub blocks: usize,   // reachable blocks, including directories
+    pub repaired: bool,  // problems were fixed on disk
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Outcome of walking one chain
+struct Chain {
+    len: usize,          // valid blocks before the end or the first bad pointer
+    last: Option<Block>, // last valid block, None if the first address is already bad
+    broken: bool,
+}
+
+// Names found for the node of a file with hard links
+struct Names {
+    path: String, // first name found
+    links: u32,   // link count of the node
+    found: u32,
+    broken: bool, // the node itself cannot be read
+}
+
+struct Checker {
+    sb: Superblock,
+    owner: Vec<u32>,              // chain id owning each data block, 0 if unreachable
+    chain: u32,                   // id of the chain being walked
+    repair: bool,
+    quarantine: Vec<(Dir, String, bool)>, // entries to move, and whether their content is lost
+    cleared: Vec<(Dir, String)>,          // entries whose content is lost, to give an empty block
+    nodes: BTreeMap<u32, Names>,
+    report: Report,
+}
+
+impl Checker {
+    fn is_data(&self, addr: u32) -> bool {
+        self.sb.data_addr() <= addr && addr < self.sb.block_count()
+    }
+
+    fn index(&self, addr: u32) -> usize {
+        (addr - self.sb.data_addr()) as usize
+    }
+
+    // Follow a chain from `addr`, recording its blocks, until its end or a bad pointer
+    fn walk_chain(&mut self, path: &str, mut addr: u32) -> Result<Chain, FsError> {
+        self.chain += 1;
+        let mut chain = Chain { len: 0, last: None, broken: false };
+        loop {
+            let problem = if !self.is_data(addr) {
+                Some(Problem::Dangling(path.into(), addr))
+            } else if self.owner[self.index(addr)] == self.chain {
+                Some(Problem::Loop(path.into(), addr))
+            } else if self.owner[self.index(addr)] != 0 {
+                Some(Problem::ClaimedTwice(path.into(), addr))
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                self.report.problems.push(problem);
+                chain.broken = true;
+                return Ok(chain);
+            }
+
+            // The chain is cut before a block that fails its checksum, which
+            // is then left unreachable
+            let block = match Block::read(addr) {
+                Ok(block) => block,
+                Err(FsError::Io) => {
+                    self.report.problems.push(Problem::Unreadable(path.into(), addr));
+                    chain.broken = true;
+                    return Ok(chain);
+                }
+                Err(e) => return Err(e),
+            };
+            let i = self.index(addr);
+            self.owner[i] = self.chain;
+            chain.len += 1;
+            addr = block.next_addr();
+            chain.last = Some(block);
+            if addr == 0 {
+                return Ok(chain);
+            }
+        }
+    }
+
+    // Check every entry of a directory whose own chain was already walked
+    fn walk_dir(&mut self, path: &str, mut dir: Dir) -> Result<(), FsError> {
+        // Collect entries first: repair may rewrite them
+        let entries: Vec<DirEntry> = dir.read()?.collect();
+        for entry in entries {
+            let child = if path == "/" {
+                format!("/{}", entry.name())
+            } else {
+                format!("{}/{}", path, entry.name())
+            };
+            self.check_entry(&mut dir, &child, &entry)?;
+        }
+        Ok(())
+    }
+
+    fn check_entry(&mut self, dir: &mut Dir, path: &str, entry: &DirEntry) -> Result<(), FsError> {
+        // The names of a file with hard links share a node and a chain, which
+        // are checked with the first name. An entry whose node is bad is
+        // removed, and its chain freed with the unreachable blocks.
+        if let Some(node) = entry.node() {
+            if let Some(names) = self.nodes.get_mut(&node) {
+                self.report.files += 1;
+                if names.broken && self.repair {
+                    return dir.remove_entry(&entry.name()).map(|_| ());
+                }
+                names.found += 1;
+                return Ok(());
+            }
+            let broken = self.walk_chain(path, node)?.broken;
+            self.nodes.insert(node, Names { path: path.into(), links: entry.links(), found: 1, broken });
+            if broken {
+                self.report.files += 1;
+                if self.repair {
+                    dir.remove_entry(&entry.name())?;
+                }
+                return Ok(());
+            }
+        }
+
+        let chain = self.walk_chain(path, entry.addr())?;
+        // The chain of a compressed file holds its compressed data, whose
+        // length is in the first block
+        let capacity = chain.len * DATA_SIZE;
+        let size = if entry.is_compressed() && chain.len > 0 {
+            entry.to_file().header_len()?.min(u32::MAX as usize) as u32
+        } else {
+            entry.size()
+        };
+        let oversized = !entry.is_dir() && size as usize > capacity;
+        if oversized {
+            self.report.problems.push(Problem::SizeExceedsChain(path.into(), size, capacity));
+        }
+
+        if self.repair && (chain.broken || oversized) {
+            match chain.last {
+                // Nothing of the content is left to save: the entry gets an
+                // empty block once the bitmap is fixed
+                None => {
+                    self.cleared.push((*dir, entry.name()));
+                    if path != LOST_AND_FOUND {
+                        self.quarantine.push((*dir, entry.name(), true));
+                    }
+                    if entry.is_dir() {
+                        self.report.dirs += 1;
+                    } else {
+                        self.report.files += 1;
+                    }
+                    return Ok(());
+                }
+                Some(mut block) => {
+                    if chain.broken {
+                        block.set_next(0);
+                        block.write()?;
+                    }
+                }
+            }
+            // What is left of compressed data cannot be decompressed
+            if oversized {
+                entry.to_file().truncate(if entry.is_compressed() { 0 } else { capacity })?;
+            }
+            if path != LOST_AND_FOUND {
+                self.quarantine.push((*dir, entry.name(), false));
+            }
+        }
+
+        if entry.is_dir() {
+            self.report.dirs += 1;
+            // A broken chain may loop, so it is only read once it has been cut
+            if !chain.broken || self.repair {
+                self.walk_dir(path, entry.to_dir())?;
+            }
+        } else {
+            self.report.files += 1;
+        }
+        Ok(())
+    }
+
+    // Compare the link count of each node with the names found for it
+    fn check_links(&mut self) -> Result<(), FsError> {
+        for (&addr, names) in &self.nodes {
+            if names.broken || names.links == names.found {
+                continue;
+            }
+            self.report.problems.push(Problem::LinkCount(names.path.clone(), names.links, names.found));
+            if self.repair {
+                let mut node = Node::read(addr)?;
+                node.set_links(names.found);
+                node.write()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Compare reachable blocks with the bitmap
+    fn check_bitmap(&mut self) -> Result<(), FsError> {
+        for addr in self.sb.data_addr()..self.sb.block_count() {
+            let reachable = self.owner[self.index(addr)] != 0;
+            let free = BlockBitmap::is_free(addr)?;
+            if reachable {
+                self.report.blocks += 1;
+            }
+            if reachable && free {
+                self.report.problems.push(Problem::Unmarked(addr));
+                if self.repair {
+                    BlockBitmap::alloc(addr)?;
+                }
+            } else if !reachable && !free {
+                self.report.problems.push(Problem::Leaked(addr));
+                if self.repair {
+                    BlockBitmap::free(addr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Move the entries whose chain was cut to /lost+found, named after their
+    // first block. An entry whose content is lost first gets an empty block,
+    // and keeps its name unless /lost+found already has it.
+    fn move_to_lost_and_found(&mut self) -> Result<(), FsError> {
+        for (mut dir, name) in core::mem::take(&mut self.cleared) {
+            dir.clear_entry(&name)?;
+        }
+        if self.quarantine.is_empty() {
+            return Ok(());
+        }
+        let mut lost = match Dir::open(LOST_AND_FOUND) {
+            Ok(dir) => dir,
+            Err(FsError::NotFound) => Dir::create(LOST_AND_FOUND)?,
+            Err(e) => return Err(e),
+        };
+        for (mut dir, name, lost_content) in core::mem::take(&mut self.quarantine) {
+            let addr = dir.find(&name)?.addr();
+            let new_name = match lost.find(&name) {
+                Err(FsError::NotFound) if lost_content => name.clone(),
+                _ => format!("#{}", addr),
+            };
+            dir.move_entry(&name, &mut lost, &new_name)?;
+        }
+        Ok(())
+    }
+}
+
+// Check the mounted volume, and fix what was found when `repair` is set:
+// chains are cut before their first bad pointer, sizes are shrunk to fit the
+// chain, the bitmap is rewritten from the reachable blocks, and the damaged
+// entries are moved to /lost+found
+pub fn check(repair: bool) -> Result<Report, FsError> {
+    let sb = ParvaFS::superblock()?;
+    let report = Report { problems: Vec::new(), dirs: 1, files: 0, blocks: 0, repaired: false };
+    let mut checker = Checker {
+        owner: vec![0; sb.data_count() as usize],
+        sb,
+        chain: 0,
+        repair,
+        quarantine: Vec::new(),
+        cleared: Vec::new(),
+        nodes: BTreeMap::new(),
+        report,
+    };
+
+    let root = Dir::root();
+    let chain = checker.walk_chain("/", root.addr())?;
+    if chain.broken {
+        if !repair {
+            return Ok(checker.report);
+        }
+        if let Some(mut block) = chain.last {
+            block.set_next(0);
+            block.write()?;
+        }
+    }
+    checker.walk_dir("/", root)?;
+    checker.check_links()?;
+
+    // The bitmap is fixed before /lost+found may need a new block
+    checker.check_bitmap()?;
+    if repair {
+        checker.move_to_lost_and_found()?;
+        checker.report.repaired = !checker.report.is_clean();
+    }
+    Ok(checker.report)
+}
+
+// Result of a scrub
+pub struct ScrubReport {
+    pub checked: usize, // blocks read
+    pub bad: Vec<u32>,  // blocks with a bad checksum, or that the disk failed to read
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.bad.is_empty()
+    }
+}
+
+// Read every bitmap block and every allocated data block of the mounted volume
+// and verify its checksum. The cache is synced and emptied first, so that
+// blocks come from the disk and not from memory. The data blocks tracked by a
+// bad bitmap block are skipped, since it is not known which ones are in use.
+pub fn scrub() -> Result<ScrubReport, FsError> {
+    let sb = ParvaFS::superblock()?;
+    ParvaFS::sync()?;
+    cache::invalidate();
+    let mut report = ScrubReport { checked: 0, bad: Vec::new() };
+    for addr in sb.bitmap_addr()..sb.block_count() {
+        if addr >= sb.data_addr() {
+            match BlockBitmap::is_free(addr) {
+                Ok(false) => {}
+                Ok(true) | Err(FsError::Io) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        report.checked += 1;
+        match Block::read(addr) {
+            Ok(_) => {}
+            Err(FsError::Io) => report.bad.push(addr),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,384 @@
+// Write-ahead journal for ParvaFS
+//
+// Every `Block::write` belongs to a transaction: multi-block operations open
+// one explicitly and a lone write is a transaction of its own. The journal
+// holds the metadata of the volume (bitmap, directories, nodes, and the blocks
+// of a chain whose next pointer changes) but not file data, like the ordered
+// mode of ext3:
+//
+// * A block the transaction allocated is written in place, through the
+//   cache: nothing committed points to it yet, so a crash cannot expose it.
+//   `File` moves the data blocks it overwrites to new blocks for this reason.
+// * Any other block is only staged in memory, and `Block::read` returns the
+//   staged copy.
+//
+// When the outermost transaction ends successfully it is committed:
+//
+//   1. the blocks written in place are flushed to the disk,
+//   2. the staged blocks are appended to the journal region, each run of up
+//      to MAX_BLOCKS of them after a descriptor block listing their home
+//      addresses,
+//   3. one commit block with the sequence number of the transaction is written,
+//   4. the staged blocks are handed to the block cache, which writes them to
+//      their home addresses later.
+//
+// When the journal is full, or on `checkpoint`, the cache is flushed and the
+// journal starts over from its first block. It also starts over before a
+// block written in place is committed while an older copy of it is in the
+// journal, which replay would write over it. A crash before step 3 leaves the
+// volume as it was before the transaction; on mount, the committed
+// transactions found in the journal are replayed in order. When a transaction
+// fails, its staged blocks are dropped and only free blocks were written. A
+// transaction whose metadata would not fit in the whole journal fails with
+// NoSpace when it stages the block that does not fit.
+//
+// Journal region: | descriptor | block 1 .. block n | descriptor | ... | commit | descriptor | ...
+//
+// descriptor: | "PARVAJNL" | seq (4) | n (4) | home addr (4) * n |
+// commit:     | "PARVACMT" | seq (4) |
+//
+// The descriptors of a transaction share its sequence number. Sequence
+// numbers of consecutive transactions follow each other, which tells them
+// apart from leftovers of the journal's previous round. After a checkpoint,
+// the first block holds a descriptor with n = 0.
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use super::cache;
+use super::ParvaFS::{superblock, volume, BlockBitmap, FsError, Superblock};
+
+const DESCRIPTOR_MAGIC: &[u8] = b"PARVAJNL";
+const COMMIT_MAGIC: &[u8] = b"PARVACMT";
+
+// Number of home addresses that fit in a descriptor block; a transaction
+// writing more blocks than this needs several descriptors
+pub const MAX_BLOCKS: usize = (512 - 16) / 4;
+
+// Smallest journal that holds a transaction of MAX_BLOCKS blocks
+pub const MIN_SIZE: u32 = 1 + MAX_BLOCKS as u32 + 1;
+
+// Journal blocks taken by a transaction of `n` blocks, with its descriptors and commit
+fn journal_len(n: usize) -> u32 {
+    let descriptors = n / MAX_BLOCKS + (n % MAX_BLOCKS != 0) as usize;
+    (n + descriptors + 1) as u32
+}
+
+// State of the journal of a volume, kept with its device
+pub(crate) struct Journal {
+    depth: usize,                  // number of nested transactions in progress
+    seq: u32,                      // sequence number of the last descriptor
+    head: u32,                     // offset in the journal region of the next descriptor
+    blocks: Vec<(u32, [u8; 512])>, // staged blocks, in order of first write
+    allocated: BTreeSet<u32>,      // blocks allocated by the current transaction
+    freed: BTreeSet<u32>,          // blocks the current transaction freed, which committed data may still use
+    in_place: BTreeSet<u32>,       // blocks the current transaction writes in place
+    logged: BTreeSet<u32>,         // home addresses of the blocks in the journal
+}
+
+impl Journal {
+    pub(crate) fn new() -> Self {
+        Journal {
+            depth: 0,
+            seq: 0,
+            head: 0,
+            blocks: Vec::new(),
+            allocated: BTreeSet::new(),
+            freed: BTreeSet::new(),
+            in_place: BTreeSet::new(),
+            logged: BTreeSet::new(),
+        }
+    }
+}
+
+// Run `f` as one transaction; nested calls join the outermost transaction
+pub fn transaction<T, F: FnOnce() -> Result<T, FsError>>(f: F) -> Result<T, FsError> {
+    volume().journal.lock().depth += 1;
+    let res = f();
+    let (blocks, in_place) = {
+        let volume = volume();
+        let mut journal = volume.journal.lock();
+        journal.depth -= 1;
+        if journal.depth > 0 {
+            return res;
+        }
+        journal.allocated.clear();
+        journal.freed.clear();
+        (core::mem::take(&mut journal.blocks), core::mem::take(&mut journal.in_place))
+    };
+    // Staged blocks of a failed transaction are dropped, and the free block
+    // count is loaded again since it may include allocations that were undone
+    if res.is_err() && !blocks.is_empty() {
+        let _ = BlockBitmap::load();
+    }
+    let val = res?;
+    commit(&blocks, &in_place)?;
+    Ok(val)
+}
+
+// Note a block allocated by the current transaction: unless the transaction
+// freed it before, nothing committed points to it and it is written in place
+pub fn allocated(addr: u32) {
+    let volume = volume();
+    let mut journal = volume.journal.lock();
+    if journal.depth > 0 && !journal.freed.contains(&addr) {
+        journal.allocated.insert(addr);
+        journal.in_place.insert(addr);
+    }
+}
+
+// Note a block freed by the current transaction, which is no longer written
+// in place if it is allocated again
+pub fn freed(addr: u32) {
+    let volume = volume();
+    let mut journal = volume.journal.lock();
+    if journal.depth > 0 {
+        journal.in_place.remove(&addr);
+        if !journal.allocated.remove(&addr) {
+            journal.freed.insert(addr);
+        }
+    }
+}
+
+// Write a block that holds data in place for the rest of the current
+// transaction, so that a crash can leave it half written
+pub fn overwrite(addr: u32) {
+    let volume = volume();
+    let mut journal = volume.journal.lock();
+    if journal.depth > 0 {
+        journal.in_place.insert(addr);
+    }
+}
+
+// Whether the current transaction writes a block in place
+pub fn in_place(addr: u32) -> bool {
+    volume().journal.lock().in_place.contains(&addr)
+}
+
+// Write a block for the current transaction: in place, or staged. A block
+// staged before stays staged, so that its last version is the one committed.
+pub fn write(addr: u32, buf: &[u8; 512]) -> Result<(), FsError> {
+    let in_place = {
+        let volume = volume();
+        let journal = volume.journal.lock();
+        journal.in_place.contains(&addr) && !journal.blocks.iter().any(|(a, _)| *a == addr)
+    };
+    if in_place {
+        return cache::write(addr, buf);
+    }
+    stage(addr, buf)
+}
+
+// Stage a block written during the current transaction, or fail with NoSpace
+// when the transaction would no longer fit in the journal
+fn stage(addr: u32, buf: &[u8; 512]) -> Result<(), FsError> {
+    let sb = superblock()?;
+    let volume = volume();
+    let mut journal = volume.journal.lock();
+    if let Some(staged) = journal.blocks.iter_mut().find(|(a, _)| *a == addr) {
+        staged.1 = *buf;
+        return Ok(());
+    }
+    if journal_len(journal.blocks.len() + 1) > sb.journal_count() {
+        return Err(FsError::NoSpace);
+    }
+    journal.blocks.push((addr, *buf));
+    Ok(())
+}
+
+// Return the staged copy of a block written by the current transaction
+pub fn staged(addr: u32) -> Option<[u8; 512]> {
+    let volume = volume();
+    let journal = volume.journal.lock();
+    journal.blocks.iter().find(|(a, _)| *a == addr).map(|(_, buf)| *buf)
+}
+
+fn read_raw(addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+    volume().device(|block_device| block_device.read_block(addr, buf))
+}
+
+fn write_raw(addr: u32, buf: &[u8]) -> Result<(), FsError> {
+    volume().device(|block_device| block_device.write_block(addr, buf))
+}
+
+fn read_u32(buf: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+}
+
+fn write_u32(buf: &mut [u8], i: usize, val: u32) {
+    buf[i..i + 4].copy_from_slice(&val.to_be_bytes());
+}
+
+fn descriptor(seq: u32, addrs: &[u32]) -> [u8; 512] {
+    let mut buf = [0; 512];
+    buf[0..8].copy_from_slice(DESCRIPTOR_MAGIC);
+    write_u32(&mut buf, 8, seq);
+    write_u32(&mut buf, 12, addrs.len() as u32);
+    for (i, &addr) in addrs.iter().enumerate() {
+        write_u32(&mut buf, 16 + 4 * i, addr);
+    }
+    buf
+}
+
+fn commit_record(seq: u32) -> [u8; 512] {
+    let mut buf = [0; 512];
+    buf[0..8].copy_from_slice(COMMIT_MAGIC);
+    write_u32(&mut buf, 8, seq);
+    buf
+}
+
+// Flush the blocks written in place, append the staged blocks to the journal,
+// then pass them to the cache
+fn commit(blocks: &[(u32, [u8; 512])], in_place: &BTreeSet<u32>) -> Result<(), FsError> {
+    if blocks.is_empty() {
+        return cache::flush(in_place);
+    }
+    let sb = superblock()?;
+    let len = journal_len(blocks.len());
+    if len > sb.journal_count() {
+        return Err(FsError::NoSpace);
+    }
+    let (full, reused) = {
+        let volume = volume();
+        let journal = volume.journal.lock();
+        (journal.head + len > sb.journal_count(), !journal.logged.is_disjoint(in_place))
+    };
+    if full || reused {
+        checkpoint()?;
+    }
+    // The data must be on disk before the metadata that points to it is committed
+    cache::flush(in_place)?;
+    let (head, seq) = {
+        let volume = volume();
+        let journal = volume.journal.lock();
+        (journal.head, journal.seq.wrapping_add(1))
+    };
+
+    let mut pos = sb.journal_addr() + head;
+    for part in blocks.chunks(MAX_BLOCKS) {
+        let mut addrs = Vec::with_capacity(part.len());
+        for (i, (addr, buf)) in part.iter().enumerate() {
+            write_raw(pos + 1 + i as u32, buf)?;
+            addrs.push(*addr);
+        }
+        write_raw(pos, &descriptor(seq, &addrs))?;
+        pos += 1 + part.len() as u32;
+    }
+
+    // The transaction is committed once this block is on disk
+    write_raw(pos, &commit_record(seq))?;
+    {
+        let volume = volume();
+        let mut journal = volume.journal.lock();
+        journal.seq = seq;
+        journal.head = head + len;
+        journal.logged.extend(blocks.iter().map(|(addr, _)| *addr));
+    }
+
+    for (addr, buf) in blocks {
+        cache::write(*addr, buf)?;
+    }
+    Ok(())
+}
+
+// Write every cached block to its home address and empty the journal
+pub fn checkpoint() -> Result<(), FsError> {
+    cache::sync()?;
+    let sb = superblock()?;
+    let volume = volume();
+    let mut journal = volume.journal.lock();
+    if journal.head > 0 {
+        write_raw(sb.journal_addr(), &descriptor(journal.seq, &[]))?;
+        journal.head = 0;
+    }
+    journal.logged.clear();
+    Ok(())
+}
+
+// A committed transaction found in the journal
+struct Committed {
+    blocks: Vec<(u32, u32)>, // offset in the journal and home address of each block
+    end: u32,                // offset after the commit block
+}
+
+// Check the transaction whose first descriptor is `desc`, at offset `pos` of
+// the journal, and return it if it was committed
+fn committed(sb: &Superblock, desc: &[u8; 512], pos: u32) -> Result<Option<Committed>, FsError> {
+    let seq = read_u32(desc, 8);
+    let mut blocks = Vec::new();
+    let mut buf = *desc;
+    let mut pos = pos;
+    loop {
+        let n = read_u32(&buf, 12);
+        if n == 0 || n as usize > MAX_BLOCKS || pos + n + 2 > sb.journal_count() {
+            return Ok(None);
+        }
+        for i in 0..n {
+            let addr = read_u32(&buf, 16 + 4 * i as usize);
+            if addr < sb.bitmap_addr() || addr >= sb.block_count() {
+                return Ok(None);
+            }
+            blocks.push((pos + 1 + i, addr));
+        }
+        pos += 1 + n;
+
+        // Either the commit block, or the next descriptor of the same transaction
+        read_raw(sb.journal_addr() + pos, &mut buf)?;
+        if read_u32(&buf, 8) != seq {
+            return Ok(None);
+        }
+        if &buf[0..8] == COMMIT_MAGIC {
+            return Ok(Some(Committed { blocks, end: pos + 1 }));
+        }
+        if &buf[0..8] != DESCRIPTOR_MAGIC {
+            return Ok(None);
+        }
+    }
+}
+
+// Called on mount: replay the committed transactions left in the journal,
+// discard an incomplete one, and return the number of blocks replayed
+pub fn replay() -> Result<usize, FsError> {
+    let sb = superblock()?;
+    let start = sb.journal_addr();
+    let mut last: Option<u32> = None; // sequence number of the last descriptor read
+    let mut pos = 0;
+    let mut replayed = 0;
+    let mut desc = [0; 512];
+    let mut buf = [0; 512];
+    while pos < sb.journal_count() {
+        read_raw(start + pos, &mut desc)?;
+        if &desc[0..8] != DESCRIPTOR_MAGIC {
+            break;
+        }
+        let seq = read_u32(&desc, 8);
+        if let Some(last) = last {
+            if seq != last.wrapping_add(1) {
+                break; // left over from a previous round
+            }
+        }
+        last = Some(seq);
+        let transaction = match committed(&sb, &desc, pos)? {
+            Some(transaction) => transaction,
+            None => break,
+        };
+        for &(i, addr) in &transaction.blocks {
+            read_raw(start + i, &mut buf)?;
+            write_raw(addr, &buf)?;
+        }
+        replayed += transaction.blocks.len();
+        pos = transaction.end;
+    }
+
+    // Start the journal over, numbering the next transactions after the last descriptor
+    let volume = volume();
+    let mut journal = volume.journal.lock();
+    journal.seq = last.unwrap_or(0);
+    journal.head = 0;
+    journal.blocks.clear();
+    journal.logged.clear();
+    if last.is_some() {
+        write_raw(start, &descriptor(journal.seq, &[]))?;
+    }
+    Ok(replayed)
+}
@@ -0,0 +1,18 @@
+// ParvaFS: the file system of ParvaOS
+//
+// The library is `no_std` and is linked into the kernel; the `parvafs` binary
+// (main.rs) uses it on the host to build and inspect disk images.
+
+#![no_std]
+
+extern crate alloc;
+
+#[allow(non_snake_case)]
+pub mod ParvaFS;
+pub mod cache;
+pub mod crypt;
+pub mod device;
+pub mod fsck;
+pub mod journal;
+pub mod lz4;
+pub mod partition;
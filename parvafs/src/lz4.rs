@@ -0,0 +1,156 @@
+// LZ4 block compression, used for the content of compressed files
+//
+// A block is a list of sequences. Each one starts with a token: its high 4
+// bits are the number of literals and its low 4 bits the match length minus
+// 4; a field of 15 continues in the following bytes, which are added until
+// one is below 255. Then come the literals, the offset of the match back in
+// the output (2 bytes, little-endian) and the rest of the match length. The
+// last sequence has literals only.
+//
+// Only single blocks are handled, without the frame format: the size of the
+// decompressed data is stored by the caller.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::ParvaFS::FsError;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 65535;
+
+// A match starts at least 12 bytes before the end of the input and leaves
+// the last 5 bytes as literals, like the reference implementation
+const MF_LIMIT: usize = 12;
+const LAST_LITERALS: usize = 5;
+
+// Positions of the last 4-byte sequences seen, by hash
+const HASH_BITS: u32 = 12;
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn read_seq(buf: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+}
+
+// Extra bytes of a length whose field in the token is 15
+fn push_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let lit = literals.len();
+    let len = found.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((lit.min(15) as u8) << 4 | len.min(15) as u8);
+    if lit >= 15 {
+        push_len(out, lit - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if len >= 15 {
+            push_len(out, len - 15);
+        }
+    }
+}
+
+// Compress `input` into one block
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    let mut table = vec![0usize; 1 << HASH_BITS]; // position + 1, 0 when empty
+    let mut anchor = 0; // start of the literals not written yet
+    let mut i = 0;
+    while i + MF_LIMIT < input.len() {
+        let seq = read_seq(input, i);
+        let h = hash(seq);
+        let candidate = table[h];
+        table[h] = i + 1;
+        if candidate == 0 || i + 1 - candidate > MAX_OFFSET || read_seq(input, candidate - 1) != seq {
+            i += 1;
+            continue;
+        }
+        let start = candidate - 1;
+        let max = input.len() - LAST_LITERALS - i;
+        let mut len = MIN_MATCH;
+        while len < max && input[start + len] == input[i + len] {
+            len += 1;
+        }
+        push_sequence(&mut out, &input[anchor..i], Some((i - start, len)));
+        i += len;
+        anchor = i;
+    }
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+// Longest block `compress` gives for `n` bytes: incompressible input takes a
+// length byte for every 255 literals, and a token
+pub fn max_compressed_size(n: usize) -> usize {
+    n + n / 255 + 16
+}
+
+// Most bytes a block of `len` bytes decompresses to: a byte of input gives
+// at most 255 bytes of output
+pub fn max_decompressed_size(len: usize) -> usize {
+    len.saturating_mul(255)
+}
+
+fn read_len(input: &[u8], i: &mut usize, mut len: usize) -> Result<usize, FsError> {
+    if len == 15 {
+        loop {
+            let byte = *input.get(*i).ok_or(FsError::Io)?;
+            *i += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+// Decompress a block that holds `size` bytes; a block that is malformed or
+// does not give exactly `size` bytes is an Io error
+pub fn decompress(input: &[u8], size: usize) -> Result<Vec<u8>, FsError> {
+    let mut out = Vec::with_capacity(size);
+    let mut i = 0;
+    while i < input.len() {
+        let token = input[i];
+        i += 1;
+        let lit = read_len(input, &mut i, (token >> 4) as usize)?;
+        let literals = input.get(i..i + lit).ok_or(FsError::Io)?;
+        if out.len() + lit > size {
+            return Err(FsError::Io);
+        }
+        out.extend_from_slice(literals);
+        i += lit;
+        if i == input.len() {
+            break;
+        }
+
+        let offset = match input.get(i..i + 2) {
+            Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]) as usize,
+            None => return Err(FsError::Io),
+        };
+        i += 2;
+        let len = read_len(input, &mut i, (token & 15) as usize)? + MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + len > size {
+            return Err(FsError::Io);
+        }
+        // The match may overlap the bytes it produces, so copy byte by byte
+        let start = out.len() - offset;
+        for j in 0..len {
+            let byte = out[start + j];
+            out.push(byte);
+        }
+    }
+    if out.len() != size {
+        return Err(FsError::Io);
+    }
+    Ok(out)
+}
@@ -0,0 +1,321 @@
+// parvafs: build and inspect ParvaFS disk images from the host
+//
+// The image is a raw disk, like the `ParvaOS.img` that QEMU boots, and the
+// file system is found at the same place as on a real ATA drive. Every path
+// inside the image is absolute.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parvafs::device::BlockDevice;
+use parvafs::fsck;
+use parvafs::ParvaFS::{self, Dir, File, FileType, FsError, Platform, BLOCK_SIZE};
+
+const USAGE: &str = "Usage: parvafs <command> <image> [args]
+
+Commands:
+  mkfs <image> [size] [--encrypt]
+                           format the image, created with `size` MiB if given,
+                           and encrypted with $PARVAFS_PASSPHRASE (--encrypt)
+  ls <image> [dir]         list a directory (root by default)
+  cat <image> <file>       print a file
+  put <image> [-z] <src> <dst>
+                           copy a host file into the image, compressed (-z)
+  get <image> <src> <dst>  copy a file of the image to the host
+  mkdir <image> <dir>      create a directory
+  ln <image> [-s] <target> <link>
+                           give a file another name, or make a symbolic link (-s)
+  rm <image> [-r] <path>   remove a file, or a directory and all its content (-r)
+  compact <image> [dir]    rewrite a directory without its deleted entries
+  fsck <image> [--repair]  check the file system, and fix it
+  scrub <image>            verify the checksum of every block in use
+
+An encrypted image is unlocked with the passphrase in $PARVAFS_PASSPHRASE.";
+
+// A raw disk image file
+struct ImageDevice {
+    file: fs::File,
+    block_count: u32,
+}
+
+impl ImageDevice {
+    fn seek(&mut self, addr: u32) -> Result<(), FsError> {
+        let pos = addr as u64 * BLOCK_SIZE as u64;
+        self.file.seek(SeekFrom::Start(pos)).map(|_| ()).map_err(|_| FsError::Io)
+    }
+}
+
+impl BlockDevice for ImageDevice {
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        self.seek(addr)?;
+        self.file.read_exact(buf).map_err(|_| FsError::Io)
+    }
+
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError> {
+        self.seek(addr)?;
+        self.file.write_all(buf).map_err(|_| FsError::Io)
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.file.sync_data().map_err(|_| FsError::Io)
+    }
+}
+
+fn realtime() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn dir() -> String {
+    String::from("/")
+}
+
+// Errors of the tool: either from the host or from the file system
+enum Error {
+    Usage,
+    Host(String, io::Error),
+    Fs(String, FsError),
+}
+
+// Attach the object of the operation to a file system error
+fn fs_err(what: &str) -> impl Fn(FsError) -> Error + '_ {
+    move |e| Error::Fs(what.into(), e)
+}
+
+fn host_err(what: &str) -> impl Fn(io::Error) -> Error + '_ {
+    move |e| Error::Host(what.into(), e)
+}
+
+fn open_image(path: &str, size: Option<u64>) -> Result<Box<ImageDevice>, Error> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(size.is_some())
+        .open(path)
+        .map_err(host_err(path))?;
+    if let Some(size) = size {
+        file.set_len(size).map_err(host_err(path))?;
+    }
+    let len = file.metadata().map_err(host_err(path))?.len();
+    let block_count = (len / BLOCK_SIZE as u64).min(u32::MAX as u64) as u32;
+    ParvaFS::set_platform(Platform { realtime, dir });
+    Ok(Box::new(ImageDevice { file, block_count }))
+}
+
+fn passphrase() -> Option<String> {
+    env::var("PARVAFS_PASSPHRASE").ok().filter(|p| !p.is_empty())
+}
+
+fn mount(path: &str) -> Result<(), Error> {
+    let mut image: Box<dyn BlockDevice> = open_image(path, None)?;
+    if let Some(passphrase) = passphrase() {
+        image = ParvaFS::unlock(image, &passphrase).map_err(fs_err(path))?;
+    }
+    ParvaFS::mount(image).map_err(fs_err(path))
+}
+
+fn random_salt() -> Result<[u8; 16], Error> {
+    let mut salt = [0; 16];
+    let mut file = fs::File::open("/dev/urandom").map_err(host_err("/dev/urandom"))?;
+    file.read_exact(&mut salt).map_err(host_err("/dev/urandom"))?;
+    Ok(salt)
+}
+
+fn mkfs(path: &str, args: &[String]) -> Result<(), Error> {
+    let encrypt = args.last().map(|s| s.as_str()) == Some("--encrypt");
+    let args = if encrypt { &args[..args.len() - 1] } else { args };
+    let size = match args {
+        [] => None,
+        [arg] => Some(arg.parse::<u64>().map_err(|_| Error::Usage)? * 1024 * 1024),
+        _ => return Err(Error::Usage),
+    };
+    if encrypt {
+        let passphrase = passphrase().ok_or(Error::Usage)?;
+        let salt = random_salt()?;
+        let image = open_image(path, size)?;
+        ParvaFS::format_encrypted(image, &passphrase, salt).map_err(fs_err(path))?;
+    } else {
+        let image = open_image(path, size)?;
+        ParvaFS::format(image).map_err(fs_err(path))?;
+    }
+    let sb = ParvaFS::superblock().map_err(fs_err(path))?;
+    println!("{}: {} data blocks of {} bytes", path, sb.data_count(), ParvaFS::DATA_SIZE);
+    Ok(())
+}
+
+fn ls(args: &[String]) -> Result<(), Error> {
+    let pathname = args.first().map_or("/", |s| s.as_str());
+    let entries = Dir::open(pathname).and_then(|dir| dir.read()).map_err(fs_err(pathname))?;
+    for entry in entries {
+        let mut name = entry.name();
+        let kind = match entry.kind() {
+            FileType::Dir => 'd',
+            FileType::File => '-',
+            FileType::Symlink => 'l',
+        };
+        if entry.is_dir() {
+            name.push('/');
+        } else if entry.is_symlink() {
+            let target = entry.target().map_err(fs_err(&name))?;
+            name = format!("{} -> {}", name, target);
+        }
+        println!("{} {:>8} {}", kind, entry.size(), name);
+    }
+    Ok(())
+}
+
+fn read_file(pathname: &str) -> Result<Vec<u8>, Error> {
+    let mut file = File::open(pathname).map_err(fs_err(pathname))?;
+    let mut buf = vec![0; file.size()];
+    file.read(&mut buf).map_err(fs_err(pathname))?;
+    Ok(buf)
+}
+
+fn cat(args: &[String]) -> Result<(), Error> {
+    let pathname = args.first().ok_or(Error::Usage)?;
+    let buf = read_file(pathname)?;
+    io::stdout().write_all(&buf).map_err(host_err("stdout"))
+}
+
+fn put(args: &[String]) -> Result<(), Error> {
+    let compressed = args.first().map(|s| s.as_str()) == Some("-z");
+    let args = if compressed { &args[1..] } else { args };
+    if args.len() != 2 {
+        return Err(Error::Usage);
+    }
+    let buf = fs::read(&args[0]).map_err(host_err(&args[0]))?;
+    let dst = &args[1];
+    let mut file = match File::open(dst) {
+        Ok(file) => file,
+        Err(FsError::NotFound) => File::create(dst).map_err(fs_err(dst))?,
+        Err(e) => return Err(Error::Fs(dst.clone(), e)),
+    };
+    file.set_compressed(compressed).map_err(fs_err(dst))?;
+    file.write(&buf).map_err(fs_err(dst))
+}
+
+fn get(args: &[String]) -> Result<(), Error> {
+    if args.len() != 2 {
+        return Err(Error::Usage);
+    }
+    let buf = read_file(&args[0])?;
+    fs::write(&args[1], &buf).map_err(host_err(&args[1]))
+}
+
+fn mkdir(args: &[String]) -> Result<(), Error> {
+    let pathname = args.first().ok_or(Error::Usage)?;
+    Dir::create(pathname).map(|_| ()).map_err(fs_err(pathname))
+}
+
+fn ln(args: &[String]) -> Result<(), Error> {
+    match args {
+        [flag, target, link] if flag == "-s" => ParvaFS::symlink(target, link).map_err(fs_err(link)),
+        [target, link] => ParvaFS::link(target, link).map_err(fs_err(link)),
+        _ => Err(Error::Usage),
+    }
+}
+
+fn rm(args: &[String]) -> Result<(), Error> {
+    let recursive = args.first().map(|s| s.as_str()) == Some("-r");
+    let pathname = args.get(if recursive { 1 } else { 0 }).ok_or(Error::Usage)?;
+    let pathname = ParvaFS::realpath(pathname);
+    if recursive {
+        if pathname == "/" {
+            return Err(Error::Fs(pathname, FsError::InvalidPath));
+        }
+        Dir::delete_all(&pathname).map_err(fs_err(&pathname))
+    } else {
+        File::delete(&pathname).map_err(fs_err(&pathname))
+    }
+}
+
+fn compact(args: &[String]) -> Result<(), Error> {
+    let pathname = args.first().map_or("/", |s| s.as_str());
+    let freed = Dir::open(pathname).and_then(|mut dir| dir.compact()).map_err(fs_err(pathname))?;
+    println!("{}: {} blocks freed", pathname, freed);
+    Ok(())
+}
+
+// Returns whether the file system is clean, or was repaired
+fn check(path: &str, args: &[String]) -> Result<bool, Error> {
+    let repair = args.first().map(|s| s.as_str()) == Some("--repair");
+    let report = fsck::check(repair).map_err(fs_err(path))?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!("{} directories, {} files, {} blocks used", report.dirs, report.files, report.blocks);
+    if report.is_clean() {
+        println!("No problems found");
+    } else if report.repaired {
+        println!("{} problems repaired", report.problems.len());
+    } else {
+        println!("{} problems found, run with --repair to fix them", report.problems.len());
+    }
+    Ok(report.is_clean() || report.repaired)
+}
+
+// Returns whether every block in use has a valid checksum
+fn scrub(path: &str) -> Result<bool, Error> {
+    let report = fsck::scrub().map_err(fs_err(path))?;
+    for addr in &report.bad {
+        println!("block {} is corrupted or unreadable", addr);
+    }
+    println!("{} blocks checked, {} bad", report.checked, report.bad.len());
+    Ok(report.is_clean())
+}
+
+fn run(args: &[String]) -> Result<bool, Error> {
+    if args.len() < 2 {
+        return Err(Error::Usage);
+    }
+    let (cmd, path, args) = (args[0].as_str(), args[1].as_str(), &args[2..]);
+    let clean = if cmd == "mkfs" {
+        mkfs(path, args).map(|_| true)
+    } else {
+        mount(path)?;
+        match cmd {
+            "ls" => ls(args).map(|_| true),
+            "cat" => cat(args).map(|_| true),
+            "put" => put(args).map(|_| true),
+            "get" => get(args).map(|_| true),
+            "mkdir" => mkdir(args).map(|_| true),
+            "ln" => ln(args).map(|_| true),
+            "rm" => rm(args).map(|_| true),
+            "compact" => compact(args).map(|_| true),
+            "fsck" => check(path, args),
+            "scrub" => scrub(path),
+            _ => Err(Error::Usage),
+        }
+    };
+    // Leave the image with an empty journal, even after an error
+    if ParvaFS::is_mounted() {
+        ParvaFS::unmount().map_err(fs_err(path))?;
+    }
+    clean
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(Error::Usage) => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+        Err(Error::Host(what, e)) => {
+            eprintln!("parvafs: {}: {}", what, e);
+            process::exit(1);
+        }
+        Err(Error::Fs(what, e)) => {
+            eprintln!("parvafs: {}: {}", what, e);
+            process::exit(1);
+        }
+    }
+}
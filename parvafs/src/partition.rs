@@ -0,0 +1,431 @@
+// Partition tables: MBR and GPT, so that ParvaFS can share a disk with other
+// systems
+//
+// An MBR describes 4 primary partitions at offset 446 of sector 0, before the
+// 0x55AA signature. A GPT disk has a protective MBR with one partition of type
+// 0xEE over the disk, a header in sector 1 and 128 entries of 128 bytes from
+// sector 2, copied at the end of the disk with the header in the last sector;
+// the header holds a CRC32 of itself and of the entries. Logical partitions
+// of an extended MBR partition are not read, and sectors must fit in 32 bits
+// like the addresses of an ATA drive.
+//
+// New partitions start on a MiB boundary, from the second MiB, so that the
+// boot image in the first one is kept. A partition is a block device of its
+// own: like a RAM disk it has no boot image, so for ParvaFS its first sector
+// holds the superblock and the blocks before it read as zeros.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::device::BlockDevice;
+use super::ParvaFS::{crc32, FsError, BLOCK_SIZE, SUPERBLOCK_ADDR};
+
+// MBR type of a ParvaFS partition, the one set aside for OS development
+pub const MBR_PARVAFS: u8 = 0x7F;
+
+// GPT type of a ParvaFS partition, 4b4dfc32-0515-45d8-b4f6-b58ba88069f6, as
+// stored on disk (the first three fields are little-endian)
+pub const GPT_PARVAFS: [u8; 16] = [
+    0x32, 0xfc, 0x4d, 0x4b, 0x15, 0x05, 0xd8, 0x45, 0xb4, 0xf6, 0xb5, 0x8b, 0xa8, 0x80, 0x69, 0xf6,
+];
+
+// New partitions start on a multiple of this many sectors (1 MiB)
+pub const ALIGN: u32 = (1 << 20) / BLOCK_SIZE;
+
+const MBR_TABLE: usize = 446; // offset of the 4 entries in sector 0
+const MBR_PROTECTIVE: u8 = 0xEE;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRIES: usize = 128; // entries of a new table, and most that are read
+const GPT_ENTRY_SIZE: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheme {
+    Mbr,
+    Gpt,
+}
+
+// Type of a partition: a byte in an MBR, a GUID in a GPT
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Mbr(u8),
+    Gpt([u8; 16]),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partition {
+    pub index: u8,  // number of the partition, from 1: its slot in the table
+    pub start: u32, // first sector
+    pub count: u32, // number of sectors
+    pub kind: Kind,
+}
+
+impl Partition {
+    pub fn is_parvafs(&self) -> bool {
+        self.kind == Kind::Mbr(MBR_PARVAFS) || self.kind == Kind::Gpt(GPT_PARVAFS)
+    }
+}
+
+// "ParvaFS", or the type byte or GUID of another system
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Mbr(MBR_PARVAFS) | Kind::Gpt(GPT_PARVAFS) => f.write_str("ParvaFS"),
+            Kind::Mbr(kind) => write!(f, "0x{:02x}", kind),
+            Kind::Gpt(g) => write!(
+                f,
+                "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                read_le32(g, 0),
+                u16::from_le_bytes([g[4], g[5]]),
+                u16::from_le_bytes([g[6], g[7]]),
+                g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15]
+            ),
+        }
+    }
+}
+
+fn read_le32(buf: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+}
+
+fn read_le64(buf: &[u8], i: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[i..i + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn write_le32(buf: &mut [u8], i: usize, val: u32) {
+    buf[i..i + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn write_le64(buf: &mut [u8], i: usize, val: u64) {
+    buf[i..i + 8].copy_from_slice(&val.to_le_bytes());
+}
+
+fn gpt_crc(bytes: &[u8]) -> u32 {
+    !crc32(!0, bytes)
+}
+
+// The used entries of an MBR, or None if one of them cannot be a partition:
+// boot code that ends with the signature leaves garbage where the table is
+fn mbr_partitions(mbr: &[u8], sectors: u32) -> Option<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    for (i, entry) in mbr[MBR_TABLE..MBR_TABLE + 64].chunks(16).enumerate() {
+        let kind = entry[4];
+        if kind == 0 {
+            continue;
+        }
+        let start = read_le32(entry, 8);
+        let count = read_le32(entry, 12);
+        // The protective partition of a large GPT disk covers at most 2 TiB
+        let fits = kind == MBR_PROTECTIVE || start as u64 + count as u64 <= sectors as u64;
+        if entry[0] & 0x7F != 0 || start == 0 || count == 0 || !fits {
+            return None;
+        }
+        partitions.push(Partition { index: i as u8 + 1, start, count, kind: Kind::Mbr(kind) });
+    }
+    Some(partitions)
+}
+
+// A copy of the GPT, header and entries, if its signature and checksums are right
+fn read_gpt(device: &mut dyn BlockDevice, lba: u32) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut header = vec![0; BLOCK_SIZE as usize];
+    device.read_block(lba, &mut header).ok()?;
+    let size = read_le32(&header, 12) as usize;
+    if &header[0..8] != GPT_SIGNATURE || size < GPT_HEADER_SIZE || size > header.len() {
+        return None;
+    }
+    let crc = read_le32(&header, 16);
+    write_le32(&mut header, 16, 0);
+    if gpt_crc(&header[..size]) != crc {
+        return None;
+    }
+    write_le32(&mut header, 16, crc);
+
+    let entries_lba = read_le64(&header, 72);
+    let count = read_le32(&header, 80) as usize;
+    if read_le32(&header, 84) as usize != GPT_ENTRY_SIZE || count == 0 || count > GPT_ENTRIES || entries_lba > u32::MAX as u64 {
+        return None;
+    }
+    let size = count * GPT_ENTRY_SIZE;
+    let sectors = size / BLOCK_SIZE as usize + (size % BLOCK_SIZE as usize != 0) as usize;
+    let mut entries = vec![0; sectors * BLOCK_SIZE as usize];
+    for (i, sector) in entries.chunks_mut(BLOCK_SIZE as usize).enumerate() {
+        device.read_block(entries_lba as u32 + i as u32, sector).ok()?;
+    }
+    if gpt_crc(&entries[..size]) != read_le32(&header, 88) {
+        return None;
+    }
+    Some((header, entries))
+}
+
+// The partition table of a disk, kept as it is on disk so that what ParvaOS
+// does not use (boot code, GPT names and attributes) is written back
+pub struct Table {
+    pub scheme: Scheme,
+    sectors: u32,     // size of the disk
+    mbr: Vec<u8>,     // sector 0
+    header: Vec<u8>,  // GPT header
+    entries: Vec<u8>, // GPT entries, in whole sectors
+}
+
+// Read the partition table of a disk: None when sector 0 has no MBR, or only
+// boot code. A GPT whose primary copy is damaged is read from its backup, and
+// one with both copies damaged is a BadTable error.
+pub fn read(device: &mut dyn BlockDevice) -> Result<Option<Table>, FsError> {
+    let sectors = device.block_count();
+    let mut mbr = vec![0; BLOCK_SIZE as usize];
+    device.read_block(0, &mut mbr)?;
+    if mbr[510..512] != [0x55, 0xAA] {
+        return Ok(None);
+    }
+    let partitions = match mbr_partitions(&mbr, sectors) {
+        Some(partitions) => partitions,
+        None => return Ok(None),
+    };
+    let mut table = Table { scheme: Scheme::Mbr, sectors, mbr, header: Vec::new(), entries: Vec::new() };
+    if partitions.iter().any(|p| p.kind == Kind::Mbr(MBR_PROTECTIVE)) {
+        let (header, entries) = read_gpt(device, 1)
+            .or_else(|| read_gpt(device, sectors - 1))
+            .ok_or(FsError::BadTable)?;
+        table.scheme = Scheme::Gpt;
+        table.header = header;
+        table.entries = entries;
+    }
+    Ok(Some(table))
+}
+
+// Write an empty partition table on a disk, keeping the boot code of sector 0;
+// `disk_guid` identifies a GPT disk
+pub fn create(device: &mut dyn BlockDevice, scheme: Scheme, disk_guid: [u8; 16]) -> Result<Table, FsError> {
+    let sectors = device.block_count();
+    let entry_sectors = (GPT_ENTRIES * GPT_ENTRY_SIZE) as u32 / BLOCK_SIZE;
+    // Room for a partition of 1 MiB after the first one, and for the backup GPT
+    if sectors < 2 * ALIGN + entry_sectors + 1 {
+        return Err(FsError::NoSpace);
+    }
+    let mut mbr = vec![0; BLOCK_SIZE as usize];
+    device.read_block(0, &mut mbr)?;
+    mbr[MBR_TABLE..].iter_mut().for_each(|b| *b = 0);
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    let mut table = Table { scheme, sectors, mbr, header: Vec::new(), entries: Vec::new() };
+
+    if scheme == Scheme::Gpt {
+        // Protective MBR: one partition over the disk, for systems that only know MBR
+        let entry = &mut table.mbr[MBR_TABLE..MBR_TABLE + 16];
+        entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+        entry[4] = MBR_PROTECTIVE;
+        entry[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        write_le32(entry, 8, 1);
+        write_le32(entry, 12, sectors - 1);
+
+        // The locations of both copies are set by `write`
+        let mut header = vec![0; BLOCK_SIZE as usize];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        write_le32(&mut header, 8, 0x0001_0000); // revision 1.0
+        write_le32(&mut header, 12, GPT_HEADER_SIZE as u32);
+        write_le64(&mut header, 40, (2 + entry_sectors) as u64); // first usable sector
+        write_le64(&mut header, 48, (sectors - 2 - entry_sectors) as u64); // last usable sector
+        header[56..72].copy_from_slice(&disk_guid);
+        write_le32(&mut header, 80, GPT_ENTRIES as u32);
+        write_le32(&mut header, 84, GPT_ENTRY_SIZE as u32);
+        table.header = header;
+        table.entries = vec![0; GPT_ENTRIES * GPT_ENTRY_SIZE];
+    }
+    table.write(device)?;
+    Ok(table)
+}
+
+impl Table {
+    // The partitions in the table, by number
+    pub fn partitions(&self) -> Vec<Partition> {
+        if self.scheme == Scheme::Mbr {
+            return mbr_partitions(&self.mbr, self.sectors).unwrap_or_default();
+        }
+        let count = read_le32(&self.header, 80) as usize;
+        let mut partitions = Vec::new();
+        for (i, entry) in self.entries[..count * GPT_ENTRY_SIZE].chunks(GPT_ENTRY_SIZE).enumerate() {
+            let mut kind = [0; 16];
+            kind.copy_from_slice(&entry[0..16]);
+            let (first, last) = (read_le64(entry, 32), read_le64(entry, 40));
+            if kind == [0; 16] || last < first || last >= self.sectors as u64 {
+                continue;
+            }
+            let (start, count) = (first as u32, (last - first + 1) as u32);
+            partitions.push(Partition { index: i as u8 + 1, start, count, kind: Kind::Gpt(kind) });
+        }
+        partitions
+    }
+
+    // First and last sectors that partitions may use
+    fn usable(&self) -> (u32, u32) {
+        match self.scheme {
+            Scheme::Mbr => (1, self.sectors - 1),
+            Scheme::Gpt => (read_le64(&self.header, 40) as u32, read_le64(&self.header, 48) as u32),
+        }
+    }
+
+    // Add a ParvaFS partition of `count` sectors in the first free space that
+    // holds it, or in the largest free space without a count; `guid`
+    // identifies a GPT partition. The table is only changed in memory.
+    pub fn add(&mut self, count: Option<u32>, guid: [u8; 16]) -> Result<Partition, FsError> {
+        let partitions = self.partitions();
+        let slots = match self.scheme {
+            Scheme::Mbr => 4,
+            Scheme::Gpt => read_le32(&self.header, 80) as usize,
+        };
+        let index = (1..=slots).find(|&i| partitions.iter().all(|p| p.index as usize != i)).ok_or(FsError::NoSpace)?;
+
+        // Free space between the partitions, each part from a MiB boundary
+        let (first, last) = self.usable();
+        let mut used: Vec<(u32, u32)> = partitions.iter().map(|p| (p.start, p.start + p.count)).collect();
+        used.sort_unstable();
+        used.push((last + 1, last + 1));
+        let mut found: Option<(u32, u32)> = None;
+        let mut from = first.max(ALIGN);
+        for (start, end) in used {
+            let aligned = from + (ALIGN - from % ALIGN) % ALIGN;
+            let free = start.saturating_sub(aligned);
+            match count {
+                Some(count) if count > 0 && free >= count => {
+                    found = Some((aligned, count));
+                    break;
+                }
+                None if free > found.map_or(0, |(_, size)| size) => found = Some((aligned, free)),
+                _ => {}
+            }
+            from = from.max(end);
+        }
+        let (start, count) = found.ok_or(FsError::NoSpace)?;
+
+        let i = index - 1;
+        let kind = match self.scheme {
+            Scheme::Mbr => {
+                let entry = &mut self.mbr[MBR_TABLE + 16 * i..MBR_TABLE + 16 * (i + 1)];
+                entry.iter_mut().for_each(|b| *b = 0);
+                // Sectors are only given as LBA, the CHS fields say so
+                entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+                entry[4] = MBR_PARVAFS;
+                entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+                write_le32(entry, 8, start);
+                write_le32(entry, 12, count);
+                Kind::Mbr(MBR_PARVAFS)
+            }
+            Scheme::Gpt => {
+                let entry = &mut self.entries[GPT_ENTRY_SIZE * i..GPT_ENTRY_SIZE * (i + 1)];
+                entry.iter_mut().for_each(|b| *b = 0);
+                entry[0..16].copy_from_slice(&GPT_PARVAFS);
+                entry[16..32].copy_from_slice(&guid);
+                write_le64(entry, 32, start as u64);
+                write_le64(entry, 40, (start + count - 1) as u64);
+                // Name of the partition, in UTF-16
+                for (j, &c) in b"ParvaFS".iter().enumerate() {
+                    entry[56 + 2 * j] = c;
+                }
+                Kind::Gpt(GPT_PARVAFS)
+            }
+        };
+        Ok(Partition { index: index as u8, start, count, kind })
+    }
+
+    // Remove partition `index` from the table in memory; its sectors are left as they are
+    pub fn remove(&mut self, index: u8) -> Result<Partition, FsError> {
+        let partition = self.partitions().into_iter().find(|p| p.index == index).ok_or(FsError::NotFound)?;
+        let i = index as usize - 1;
+        let entry = match self.scheme {
+            Scheme::Mbr => &mut self.mbr[MBR_TABLE + 16 * i..MBR_TABLE + 16 * (i + 1)],
+            Scheme::Gpt => &mut self.entries[GPT_ENTRY_SIZE * i..GPT_ENTRY_SIZE * (i + 1)],
+        };
+        entry.iter_mut().for_each(|b| *b = 0);
+        Ok(partition)
+    }
+
+    // Write the table to the disk: both copies of a GPT, with their checksums,
+    // then sector 0
+    pub fn write(&mut self, device: &mut dyn BlockDevice) -> Result<(), FsError> {
+        if self.scheme == Scheme::Gpt {
+            let count = read_le32(&self.header, 80) as usize;
+            let entries_crc = gpt_crc(&self.entries[..count * GPT_ENTRY_SIZE]);
+            let entry_sectors = self.entries.len() as u32 / BLOCK_SIZE;
+            let last = self.sectors - 1;
+            // (header, other header, entries) of the primary copy, then of the backup
+            for &(lba, other, entries_lba) in &[(1, last, 2), (last, 1, last - entry_sectors)] {
+                let mut header = self.header.clone();
+                write_le64(&mut header, 24, lba as u64);
+                write_le64(&mut header, 32, other as u64);
+                write_le64(&mut header, 72, entries_lba as u64);
+                write_le32(&mut header, 88, entries_crc);
+                write_le32(&mut header, 16, 0);
+                let size = read_le32(&header, 12) as usize;
+                let crc = gpt_crc(&header[..size]);
+                write_le32(&mut header, 16, crc);
+                for (i, sector) in self.entries.chunks(BLOCK_SIZE as usize).enumerate() {
+                    device.write_block(entries_lba + i as u32, sector)?;
+                }
+                device.write_block(lba, &header)?;
+            }
+        }
+        device.write_block(0, &self.mbr)?;
+        device.flush()
+    }
+}
+
+// A partition of a disk, seen as a disk whose blocks from `first` on are the
+// sectors of the partition
+pub struct PartitionDevice {
+    inner: Box<dyn BlockDevice>,
+    start: u32,
+    count: u32,
+    first: u32,
+}
+
+impl PartitionDevice {
+    // The partition as ParvaFS sees it, with the superblock in its first sector
+    pub fn new(inner: Box<dyn BlockDevice>, partition: &Partition) -> Self {
+        Self { inner, start: partition.start, count: partition.count, first: SUPERBLOCK_ADDR }
+    }
+
+    // The sectors of the partition alone, from block 0
+    pub fn raw(inner: Box<dyn BlockDevice>, partition: &Partition) -> Self {
+        Self { inner, start: partition.start, count: partition.count, first: 0 }
+    }
+
+    // Sector of the disk that holds a block, if the block is in the partition
+    fn sector(&self, addr: u32) -> Result<u32, FsError> {
+        match addr.checked_sub(self.first) {
+            Some(i) if i < self.count => Ok(self.start + i),
+            _ => Err(FsError::Io),
+        }
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if addr < self.first {
+            buf.iter_mut().for_each(|b| *b = 0);
+            return Ok(());
+        }
+        let sector = self.sector(addr)?;
+        self.inner.read_block(sector, buf)
+    }
+
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError> {
+        let sector = self.sector(addr)?;
+        self.inner.write_block(sector, buf)
+    }
+
+    fn block_count(&self) -> u32 {
+        self.first + self.count
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.inner.flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
@@ -0,0 +1,894 @@
+// Randomized tests of ParvaFS on an in-memory device
+//
+// Sequences of random operations run both on the file system and on a simple
+// model of the tree, and the two are compared after every few steps. The crash
+// test drops every write after a random point, as a power loss would, and
+// checks that the volume mounts again with each file either before or after
+// its last write.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use parvafs::crypt::Xts;
+use parvafs::device::{BlockDevice, MemDevice};
+use parvafs::fsck;
+use parvafs::journal;
+use parvafs::partition::{self, Kind, PartitionDevice, Scheme, ALIGN, GPT_PARVAFS, MBR_PARVAFS};
+use parvafs::ParvaFS::{self, Block, BlockBitmap, Dir, File, FileHandle, FsError, Platform, Volume, DATA_SIZE, SUPERBLOCK_ADDR};
+
+// The file system is global, so tests must not run at the same time
+static LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+const BLOCKS: u32 = 8192; // 4 MiB
+
+// xorshift64*, to replay a failing sequence from its seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // Random bytes, fewer than `max`
+    fn bytes(&mut self, max: usize) -> Vec<u8> {
+        let len = self.below(max);
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Node {
+    Dir,
+    File(Vec<u8>),
+}
+
+// Absolute path -> node; the root is implicit
+type Model = BTreeMap<String, Node>;
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir == "/" { format!("/{}", name) } else { format!("{}/{}", dir, name) }
+}
+
+fn is_dir(model: &Model, path: &str) -> bool {
+    path == "/" || model.get(path) == Some(&Node::Dir)
+}
+
+fn children<'a>(model: &'a Model, dir: &str) -> Vec<&'a String> {
+    model.keys().filter(|p| parent(p) == dir).collect()
+}
+
+// A random path: an existing one most of the time, or a new name in an existing directory
+fn pick(rng: &mut Rng, model: &Model) -> String {
+    let paths: Vec<&String> = model.keys().collect();
+    if paths.is_empty() || rng.below(3) == 0 {
+        let dirs: Vec<&str> = std::iter::once("/")
+            .chain(model.iter().filter(|(_, n)| **n == Node::Dir).map(|(p, _)| p.as_str()))
+            .collect();
+        let dir = dirs[rng.below(dirs.len())];
+        join(dir, ["a", "b", "c", "d", "e"][rng.below(5)])
+    } else {
+        paths[rng.below(paths.len())].clone()
+    }
+}
+
+fn pick_file(rng: &mut Rng, model: &Model) -> Option<String> {
+    let files: Vec<&String> = model.iter().filter(|(_, n)| **n != Node::Dir).map(|(p, _)| p).collect();
+    if files.is_empty() { None } else { Some(files[rng.below(files.len())].clone()) }
+}
+
+fn read_all(path: &str) -> Vec<u8> {
+    let mut file = File::open(path).unwrap();
+    let mut buf = vec![0; file.size()];
+    assert_eq!(file.read(&mut buf).unwrap(), buf.len());
+    buf
+}
+
+// Like read_all, for files that may be damaged
+fn try_read_all(path: &str) -> Result<Vec<u8>, FsError> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; file.size()];
+    let n = file.read(&mut buf)?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+// Names of the entries of a directory, in order
+fn names(dir: &str) -> Vec<String> {
+    Dir::open(dir).unwrap().read().unwrap().map(|entry| entry.name()).collect()
+}
+
+// Compare the whole tree with the model, and check the volume with fsck
+fn verify(model: &Model) {
+    let mut dirs = vec![String::from("/")];
+    while let Some(dir) = dirs.pop() {
+        let mut names: Vec<String> = Dir::open(&dir).unwrap().read().unwrap().map(|e| e.name()).collect();
+        names.sort();
+        let mut expected: Vec<String> = children(model, &dir).iter().map(|p| ParvaFS::filename(p).to_string()).collect();
+        expected.sort();
+        assert_eq!(names, expected, "entries of {}", dir);
+        for path in children(model, &dir) {
+            match &model[path] {
+                Node::Dir => dirs.push(path.clone()),
+                Node::File(data) => assert!(read_all(path) == *data, "content of {}", path),
+            }
+        }
+    }
+    let report = fsck::check(false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let sb = ParvaFS::superblock().unwrap();
+    assert_eq!(BlockBitmap::free_count().unwrap(), sb.data_count() - report.blocks as u32);
+}
+
+// Run one random operation on both sides
+fn step(rng: &mut Rng, model: &mut Model) {
+    match rng.below(11) {
+        0 => {
+            let path = pick(rng, model);
+            let ok = !model.contains_key(&path) && is_dir(model, parent(&path));
+            assert_eq!(File::create(&path).is_ok(), ok, "create {}", path);
+            if ok {
+                model.insert(path, Node::File(Vec::new()));
+            }
+        }
+        1 => {
+            let path = pick(rng, model);
+            let ok = !model.contains_key(&path) && is_dir(model, parent(&path));
+            assert_eq!(Dir::create(&path).is_ok(), ok, "mkdir {}", path);
+            if ok {
+                model.insert(path, Node::Dir);
+            }
+        }
+        2 | 3 => {
+            if let Some(path) = pick_file(rng, model) {
+                let data = match model.get_mut(&path) { Some(Node::File(data)) => data, _ => unreachable!() };
+                let offset = rng.below(data.len() + DATA_SIZE + 1);
+                let buf = rng.bytes(3 * DATA_SIZE);
+                File::open(&path).unwrap().write_at(offset, &buf).unwrap();
+                if !buf.is_empty() || offset > data.len() {
+                    let end = offset + buf.len();
+                    if data.len() < end {
+                        data.resize(end, 0);
+                    }
+                    data[offset..end].copy_from_slice(&buf);
+                }
+            }
+        }
+        4 => {
+            if let Some(path) = pick_file(rng, model) {
+                let buf = rng.bytes(4 * DATA_SIZE);
+                File::open(&path).unwrap().write(&buf).unwrap();
+                model.insert(path, Node::File(buf));
+            }
+        }
+        5 => {
+            if let Some(path) = pick_file(rng, model) {
+                let data = match model.get_mut(&path) { Some(Node::File(data)) => data, _ => unreachable!() };
+                let len = rng.below(data.len() + 1);
+                File::open(&path).unwrap().truncate(len).unwrap();
+                data.truncate(len);
+            }
+        }
+        6 => {
+            let path = pick(rng, model);
+            match model.get(&path) {
+                None => assert_eq!(File::delete(&path), Err(FsError::NotFound)),
+                Some(Node::File(_)) => {
+                    File::delete(&path).unwrap();
+                    model.remove(&path);
+                }
+                Some(Node::Dir) if children(model, &path).is_empty() => {
+                    Dir::delete(&path).unwrap();
+                    model.remove(&path);
+                }
+                Some(Node::Dir) => assert_eq!(Dir::delete(&path), Err(FsError::NotEmpty)),
+            }
+        }
+        7 => {
+            let src = pick(rng, model);
+            let dst = pick(rng, model);
+            // Renaming an entry to its own path does nothing
+            let ok = model.contains_key(&src)
+                && (src == dst || !model.contains_key(&dst))
+                && is_dir(model, parent(&dst))
+                && !dst.starts_with(&format!("{}/", src));
+            assert_eq!(ParvaFS::rename(&src, &dst).is_ok(), ok, "rename {} {}", src, dst);
+            if ok && src != dst {
+                let moved: Vec<String> = model
+                    .keys()
+                    .filter(|p| **p == src || p.starts_with(&format!("{}/", src)))
+                    .cloned()
+                    .collect();
+                for path in moved {
+                    let node = model.remove(&path).unwrap();
+                    model.insert(format!("{}{}", dst, &path[src.len()..]), node);
+                }
+            }
+        }
+        8 => {
+            if let Some(path) = pick_file(rng, model) {
+                let data = match &model[&path] { Node::File(data) => data.clone(), _ => unreachable!() };
+                let offset = rng.below(data.len() + 1);
+                let mut buf = vec![0; rng.below(2 * DATA_SIZE)];
+                let n = File::open(&path).unwrap().read_at(offset, &mut buf).unwrap();
+                let end = (offset + buf.len()).min(data.len());
+                assert_eq!(&buf[..n], &data[offset..end], "read {} at {}", path, offset);
+            }
+        }
+        9 => {
+            if let Some(path) = pick_file(rng, model) {
+                let mut file = File::open(&path).unwrap();
+                let compressed = !file.is_compressed();
+                file.set_compressed(compressed).unwrap();
+            }
+        }
+        _ => {
+            let block_device = ParvaFS::unmount().unwrap();
+            ParvaFS::mount(block_device).unwrap();
+        }
+    }
+}
+
+#[test]
+fn random_operations_match_model() {
+    let _lock = LOCK.lock();
+    for seed in 1..=8u64 {
+        let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        let mut model = Model::new();
+        ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+        for i in 0..400 {
+            step(&mut rng, &mut model);
+            if i % 50 == 0 {
+                verify(&model);
+            }
+        }
+        verify(&model);
+        ParvaFS::unmount().unwrap();
+    }
+}
+
+#[test]
+fn small_volume_gets_smaller_journal() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(SUPERBLOCK_ADDR + 512))).unwrap();
+    let sb = ParvaFS::superblock().unwrap();
+    assert_eq!(sb.journal_count(), journal::MIN_SIZE);
+    let mut model = Model::new();
+    let data: Vec<u8> = (0..300 * DATA_SIZE).map(|i| i as u8).collect();
+    File::create("/big").unwrap().write(&data).unwrap();
+    model.insert(String::from("/big"), Node::File(data));
+    let block_device = ParvaFS::unmount().unwrap();
+    ParvaFS::mount(block_device).unwrap();
+    verify(&model);
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn large_transaction_is_all_or_nothing() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    let free = BlockBitmap::free_count().unwrap();
+
+    // A failed transaction leaves nothing, neither its metadata nor its data
+    let data: Vec<u8> = (0..200 * DATA_SIZE).map(|i| i as u8).collect();
+    let result = journal::transaction(|| {
+        File::create("/big")?.write(&data)?;
+        Err::<(), _>(FsError::Io)
+    });
+    assert_eq!(result, Err(FsError::Io));
+    assert_eq!(File::open("/big").err(), Some(FsError::NotFound));
+    let block_device = ParvaFS::unmount().unwrap();
+    ParvaFS::mount(block_device).unwrap();
+    assert_eq!(File::open("/big").err(), Some(FsError::NotFound));
+    assert_eq!(BlockBitmap::free_count().unwrap(), free);
+
+    // More journaled blocks than one descriptor lists, then a failure
+    File::create("/big").unwrap().write(&data).unwrap();
+    let rewrite = || {
+        let mut block = Some(Block::read(ParvaFS::stat("/big")?.addr())?);
+        while let Some(mut b) = block {
+            b.data_mut().fill(0);
+            b.write()?;
+            block = b.next()?;
+        }
+        Ok(())
+    };
+    let result = journal::transaction(|| rewrite().and(Err::<(), _>(FsError::Io)));
+    assert_eq!(result, Err(FsError::Io));
+    assert!(read_all("/big") == data);
+    journal::transaction(rewrite).unwrap();
+    let block_device = ParvaFS::unmount().unwrap();
+    ParvaFS::mount(block_device).unwrap();
+    assert!(read_all("/big") == vec![0; data.len()]);
+    File::delete("/big").unwrap();
+
+    // File data does not go through the journal: a write larger than the
+    // journal succeeds, and so does overwriting it
+    let data: Vec<u8> = (0..1000 * DATA_SIZE).map(|i| i as u8).collect();
+    File::create("/huge").unwrap().write(&data).unwrap();
+    let data: Vec<u8> = data.iter().map(|b| !b).collect();
+    File::open("/huge").unwrap().write(&data).unwrap();
+    let block_device = ParvaFS::unmount().unwrap();
+    ParvaFS::mount(block_device).unwrap();
+    assert!(read_all("/huge") == data);
+    assert_eq!(BlockBitmap::free_count().unwrap(), free - 1000);
+    assert!(fsck::check(false).unwrap().is_clean());
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn binary_file_is_not_read_as_text() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    File::create("/text").unwrap().write("héllo".as_bytes()).unwrap();
+    File::create("/binary").unwrap().write(&[0x68, 0xff, 0x00, 0xc3]).unwrap();
+    assert_eq!(File::open("/text").unwrap().read_to_string(), Ok(String::from("héllo")));
+    assert_eq!(File::open("/binary").unwrap().read_to_string(), Err(FsError::InvalidData));
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn compressed_file_is_read_back() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    let text = "All work and no play makes Jack a dull boy.\n".repeat(200);
+    let mut file = File::create("/text").unwrap();
+    file.write(text.as_bytes()).unwrap();
+    let free = BlockBitmap::free_count().unwrap();
+    file.set_compressed(true).unwrap();
+    assert!(BlockBitmap::free_count().unwrap() > free);
+
+    let mut file = File::open("/text").unwrap();
+    assert!(file.is_compressed());
+    assert_eq!(file.size(), text.len());
+    assert!(file.stored_size().unwrap() < DATA_SIZE);
+    assert_eq!(file.read_to_string(), Ok(text));
+
+    // Partial writes and truncation go through the whole content
+    file.write_at(4, b"WORK").unwrap();
+    file.truncate(44).unwrap();
+    file.write_at(50, b"!").unwrap();
+    let mut expected = b"All WORK and no play makes Jack a dull boy.\n".to_vec();
+    expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, b'!']);
+    assert_eq!(read_all("/text"), expected);
+    assert!(fsck::check(false).unwrap().is_clean());
+
+    let block_device = ParvaFS::unmount().unwrap();
+    ParvaFS::mount(block_device).unwrap();
+    let mut file = File::open("/text").unwrap();
+    assert!(file.is_compressed());
+    file.set_compressed(false).unwrap();
+    assert_eq!(file.stored_size().unwrap(), expected.len());
+    assert_eq!(read_all("/text"), expected);
+
+    // A file read in small parts is decompressed once
+    let text = "0123456789".repeat(500);
+    let mut file = File::create("/parts").unwrap();
+    file.write(text.as_bytes()).unwrap();
+    file.set_compressed(true).unwrap();
+    let mut handle = FileHandle::open("/parts").unwrap();
+    let mut read = Vec::new();
+    let mut buf = [0; 16];
+    loop {
+        let n = handle.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        read.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(read, text.as_bytes());
+
+    // A compressed length the chain cannot hold is an error, not an allocation
+    let mut block = Block::read(file.addr()).unwrap();
+    block.data_mut()[..4].copy_from_slice(&0xFFFF_FFF0u32.to_be_bytes());
+    block.write().unwrap();
+    let mut file = File::open("/parts").unwrap();
+    assert_eq!(file.stored_size(), Err(FsError::Io));
+    assert_eq!(file.read(&mut buf), Err(FsError::Io));
+    assert!(!fsck::check(false).unwrap().is_clean());
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn volumes_are_kept_apart() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    File::create("/mounted").unwrap();
+    assert_eq!(ParvaFS::format(Box::new(MemDevice::new(BLOCKS))), Err(FsError::Busy));
+    let volumes: Vec<Volume> = (0..2).map(|_| Volume::format(Box::new(MemDevice::new(BLOCKS))).unwrap()).collect();
+    let mut models = vec![Model::new(), Model::new()];
+    let mut rng = Rng(7);
+    for _ in 0..200 {
+        let i = rng.below(2);
+        let model = &mut models[i];
+        volumes[i].run(|| {
+            step(&mut rng, model);
+            Ok(())
+        })
+        .unwrap();
+    }
+    for (volume, model) in volumes.iter().zip(&models) {
+        volume.run(|| {
+            verify(model);
+            Ok(())
+        })
+        .unwrap();
+    }
+    // The volume mounted before is still the current one
+    let names: Vec<String> = Dir::open("/").unwrap().read().unwrap().map(|e| e.name()).collect();
+    assert_eq!(names, ["mounted"]);
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn volume_state_survives_panic() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    File::create("/mounted").unwrap();
+    let volume = Volume::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    volume.run(|| File::create("/aside").map(|_| ())).unwrap();
+
+    // A panic in the middle of a call leaves both volumes where they were
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        volume.run(|| -> Result<(), FsError> { panic!("in the middle of a call") })
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(volume.run(|| Ok(names("/"))), Ok(vec![String::from("aside")]));
+    assert_eq!(names("/"), vec!["mounted"]);
+    volume.run(ParvaFS::unmount).unwrap();
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn nested_runs_use_their_own_volume() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    let src = Volume::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    let dst = Volume::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    src.run(|| File::create("/file")?.write(b"content")).unwrap();
+
+    // A copy from one volume to another, each call on the volume of its run
+    let copied = src.run(|| {
+        let data = read_all("/file");
+        dst.run(|| File::create("/copy")?.write(&data))?;
+        src.run(|| Ok(names("/")))
+    });
+    assert_eq!(copied, Ok(vec![String::from("file")]));
+    assert_eq!(dst.run(|| Ok(read_all("/copy"))), Ok(b"content".to_vec()));
+    assert!(names("/").is_empty());
+    src.run(ParvaFS::unmount).unwrap();
+    dst.run(ParvaFS::unmount).unwrap();
+    ParvaFS::unmount().unwrap();
+}
+
+// A device whose blocks outlive the mount, and which stops writing when
+// `writes_left` reaches 0 (-1: no limit). The mount that made the lost
+// writes still reads them back, until `power_on`.
+#[derive(Clone)]
+struct CrashDevice {
+    data: Arc<Mutex<Vec<u8>>>,
+    lost: Arc<Mutex<BTreeMap<u32, Vec<u8>>>>,
+    writes_left: Arc<AtomicIsize>,
+}
+
+impl CrashDevice {
+    fn new(blocks: usize) -> Self {
+        CrashDevice {
+            data: Arc::new(Mutex::new(vec![0; blocks * 512])),
+            lost: Arc::new(Mutex::new(BTreeMap::new())),
+            writes_left: Arc::new(AtomicIsize::new(-1)),
+        }
+    }
+
+    // Forget the lost writes and write again without limit
+    fn power_on(&self) {
+        self.lost.lock().unwrap().clear();
+        self.writes_left.store(-1, Ordering::SeqCst);
+    }
+}
+
+impl BlockDevice for CrashDevice {
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if let Some(lost) = self.lost.lock().unwrap().get(&addr) {
+            buf.copy_from_slice(lost);
+            return Ok(());
+        }
+        let i = addr as usize * 512;
+        buf.copy_from_slice(&self.data.lock().unwrap()[i..i + 512]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError> {
+        let left = self.writes_left.load(Ordering::SeqCst);
+        if left == 0 {
+            // the power is off, the write never reaches the disk
+            self.lost.lock().unwrap().insert(addr, buf.to_vec());
+            return Ok(());
+        }
+        self.writes_left.store(left - 1, Ordering::SeqCst);
+        let i = addr as usize * 512;
+        self.data.lock().unwrap()[i..i + 512].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        (self.data.lock().unwrap().len() / 512) as u32
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+#[test]
+fn crash_leaves_files_before_or_after_write() {
+    let _lock = LOCK.lock();
+    let device = CrashDevice::new(BLOCKS as usize);
+    let mut volume = Volume::format(Box::new(device.clone())).unwrap();
+    let mut rng = Rng(42);
+    let mut files: Vec<Vec<u8>> = Vec::new();
+    for round in 0..200 {
+        // Writes of up to 200 blocks, whose data is not journaled
+        let k = rng.below(files.len() + 1);
+        let old = files.get(k).cloned();
+        let new = rng.bytes(200 * DATA_SIZE);
+        let path = format!("/f{}", k);
+
+        device.writes_left.store(rng.below(400) as isize, Ordering::SeqCst);
+        let result = volume.run(|| {
+            match old {
+                Some(_) => File::open(&path),
+                None => File::create(&path),
+            }
+            .and_then(|mut file| file.write(&new))
+        });
+        assert!(result.is_ok(), "round {}: {:?}", round, result);
+        // Once `write` returns, its transaction is committed unless writes were dropped
+        let crashed = device.writes_left.load(Ordering::SeqCst) == 0;
+
+        // Power loss: what is in memory is gone with the volume, the mount replays the journal
+        device.power_on();
+        volume = Volume::mount(Box::new(device.clone())).unwrap();
+        volume.run(|| {
+            let report = fsck::check(false).unwrap();
+            assert!(report.is_clean(), "round {}: {:?}", round, report.problems);
+
+            let data = match File::open(&path) {
+                Ok(_) => Some(read_all(&path)),
+                Err(FsError::NotFound) => None,
+                Err(e) => panic!("round {}: {}", round, e),
+            };
+            if data.as_ref() == Some(&new) {
+                if k == files.len() { files.push(new) } else { files[k] = new }
+            } else {
+                assert!(crashed, "round {}: committed write lost", round);
+                assert!(data == old || (old.is_none() && data == Some(Vec::new())), "round {}: torn write", round);
+                if let (None, Some(empty)) = (old, data) {
+                    files.push(empty); // the file was created but not written
+                }
+            }
+            for (i, data) in files.iter().enumerate() {
+                assert!(read_all(&format!("/f{}", i)) == *data, "round {}: /f{} changed", round, i);
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+    volume.run(ParvaFS::unmount).unwrap();
+}
+
+// A device that fails every write while `read_only` is set, and says so
+struct ReadOnlyDevice {
+    inner: MemDevice,
+    read_only: Arc<AtomicBool>,
+}
+
+impl BlockDevice for ReadOnlyDevice {
+    fn read_block(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        self.inner.read_block(addr, buf)
+    }
+
+    fn write_block(&mut self, addr: u32, buf: &[u8]) -> Result<(), FsError> {
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(FsError::Io);
+        }
+        self.inner.write_block(addr, buf)
+    }
+
+    fn block_count(&self) -> u32 {
+        self.inner.block_count()
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.inner.flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+}
+
+// Time returned by the platform of the tests that need one; the others see 0
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+
+#[test]
+fn read_does_not_need_writes() {
+    let _lock = LOCK.lock();
+    ParvaFS::set_platform(Platform { realtime: || CLOCK.load(Ordering::SeqCst), dir: || String::from("/") });
+    let read_only = Arc::new(AtomicBool::new(false));
+    let device = ReadOnlyDevice { inner: MemDevice::new(BLOCKS), read_only: read_only.clone() };
+    CLOCK.store(1, Ordering::SeqCst);
+    ParvaFS::format(Box::new(device)).unwrap();
+    File::create("/file").unwrap().write(b"content").unwrap();
+    ParvaFS::sync().unwrap();
+
+    // The access time is not saved, the read succeeds anyway
+    CLOCK.store(2, Ordering::SeqCst);
+    read_only.store(true, Ordering::SeqCst);
+    assert_eq!(read_all("/file"), b"content");
+    assert_eq!(ParvaFS::stat("/file").unwrap().accessed(), 1);
+
+    // Once the device can be written, the next read saves it
+    read_only.store(false, Ordering::SeqCst);
+    assert_eq!(read_all("/file"), b"content");
+    assert_eq!(ParvaFS::stat("/file").unwrap().accessed(), 2);
+    ParvaFS::unmount().unwrap();
+    CLOCK.store(0, Ordering::SeqCst);
+}
+
+#[test]
+fn corrupted_block_is_detected() {
+    let _lock = LOCK.lock();
+    let device = CrashDevice::new(BLOCKS as usize);
+    ParvaFS::format(Box::new(device.clone())).unwrap();
+    let data: Vec<u8> = (0..3 * DATA_SIZE).map(|i| i as u8).collect();
+    File::create("/keep").unwrap().write(b"keep").unwrap();
+    File::create("/file").unwrap().write(&data).unwrap();
+    let first = ParvaFS::stat("/file").unwrap().addr();
+    let second = Block::read(first).unwrap().next_addr();
+    assert!(fsck::scrub().unwrap().is_clean());
+
+    // A stray write flips one byte of the second block of the file, behind the cache
+    ParvaFS::unmount().unwrap();
+    device.data.lock().unwrap()[second as usize * 512 + 100] ^= 1;
+    ParvaFS::mount(Box::new(device.clone())).unwrap();
+    assert_eq!(try_read_all("/file"), Err(FsError::Io));
+    let report = fsck::scrub().unwrap();
+    assert_eq!(report.bad, vec![second]);
+
+    let report = fsck::check(true).unwrap();
+    assert!(report.problems.contains(&fsck::Problem::Unreadable(String::from("/file"), second)));
+    assert!(report.repaired);
+    assert!(fsck::scrub().unwrap().is_clean());
+    assert!(fsck::check(false).unwrap().is_clean());
+    let saved = format!("{}/#{}", fsck::LOST_AND_FOUND, first);
+    assert_eq!(try_read_all(&saved), Ok(data[..DATA_SIZE].to_vec()));
+    assert_eq!(try_read_all("/keep"), Ok(b"keep".to_vec()));
+
+    // With its only block bad, a file is kept by its name, empty
+    let only = ParvaFS::stat("/keep").unwrap().addr();
+    ParvaFS::unmount().unwrap();
+    device.data.lock().unwrap()[only as usize * 512 + 100] ^= 1;
+    ParvaFS::mount(Box::new(device.clone())).unwrap();
+    let report = fsck::check(true).unwrap();
+    assert!(report.problems.contains(&fsck::Problem::Unreadable(String::from("/keep"), only)));
+    assert!(fsck::check(false).unwrap().is_clean());
+    assert_eq!(File::open("/keep").err(), Some(FsError::NotFound));
+    let kept = format!("{}/keep", fsck::LOST_AND_FOUND);
+    assert_eq!(try_read_all(&kept), Ok(Vec::new()));
+    assert!(fsck::scrub().unwrap().is_clean());
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn encrypted_volume_needs_passphrase() {
+    let _lock = LOCK.lock();
+    let device = CrashDevice::new(BLOCKS as usize);
+    ParvaFS::format_encrypted(Box::new(device.clone()), "correct horse", [7; 16]).unwrap();
+    File::create("/secret").unwrap().write(b"top secret").unwrap();
+    assert!(fsck::check(false).unwrap().is_clean());
+    ParvaFS::unmount().unwrap();
+
+    // Neither the file nor its name is on the disk in clear
+    let disk = device.data.lock().unwrap().clone();
+    assert!(!disk.windows(6).any(|w| w == b"secret"));
+    assert_eq!(ParvaFS::mount(Box::new(device.clone())), Err(FsError::Locked));
+    assert_eq!(ParvaFS::unlock(Box::new(device.clone()), "wrong").err(), Some(FsError::BadPassphrase));
+    ParvaFS::mount(ParvaFS::unlock(Box::new(device), "correct horse").unwrap()).unwrap();
+    assert_eq!(read_all("/secret"), b"top secret");
+    assert!(fsck::scrub().unwrap().is_clean());
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn blocks_are_encrypted_with_xts() {
+    // Vector 1 of IEEE 1619: both keys, the unit and the data are zeros
+    let xts = Xts::new(&[0; 32]);
+    let mut buf = [0; 32];
+    xts.encrypt(0, &mut buf);
+    let expected = "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e";
+    assert_eq!(buf.iter().map(|b| format!("{:02x}", b)).collect::<String>(), expected);
+    xts.decrypt(0, &mut buf);
+    assert_eq!(buf, [0; 32]);
+
+    // A changed byte changes its whole 16-byte piece, where a key stream
+    // reused for both versions of the block would only change that byte
+    let mut old = [0; 512];
+    let mut new = [0; 512];
+    new[0] = 1;
+    xts.encrypt(7, &mut old);
+    xts.encrypt(7, &mut new);
+    assert!(old[1..16] != new[1..16]);
+    assert_eq!(old[16..], new[16..]);
+}
+
+#[test]
+fn links_share_content_and_resolve() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    let free = BlockBitmap::free_count().unwrap();
+    Dir::create("/etc").unwrap();
+    File::create("/etc/config-v1").unwrap().write(b"version 1").unwrap();
+    File::create("/etc/config-v2").unwrap().write(b"version 2").unwrap();
+
+    // A symbolic link is followed from its own directory, and can be replaced
+    ParvaFS::symlink("config-v1", "/etc/current-config").unwrap();
+    assert_eq!(read_all("/etc/current-config"), b"version 1");
+    File::delete("/etc/current-config").unwrap();
+    ParvaFS::symlink("config-v2", "/etc/current-config").unwrap();
+    assert_eq!(read_all("/etc/current-config"), b"version 2");
+    assert_eq!(ParvaFS::read_link("/etc/current-config"), Ok("config-v2".into()));
+    ParvaFS::symlink("/etc", "/conf").unwrap();
+    assert_eq!(read_all("/conf/current-config"), b"version 2");
+
+    // A loop fails instead of running forever, a dangling link reads as missing
+    ParvaFS::symlink("/b", "/a").unwrap();
+    ParvaFS::symlink("/a", "/b").unwrap();
+    assert_eq!(File::open("/a").err(), Some(FsError::LinkLoop));
+    ParvaFS::symlink("/missing", "/dangling").unwrap();
+    assert_eq!(File::open("/dangling").err(), Some(FsError::NotFound));
+
+    // Hard links share the content, which goes with the last name
+    ParvaFS::link("/etc/config-v1", "/backup").unwrap();
+    ParvaFS::link("/backup", "/etc/again").unwrap();
+    assert_eq!(ParvaFS::link("/etc", "/etc2").err(), Some(FsError::IsADirectory));
+    File::open("/backup").unwrap().write(b"changed").unwrap();
+    assert_eq!(read_all("/etc/config-v1"), b"changed");
+    assert_eq!(ParvaFS::stat("/etc/again").unwrap().links(), 3);
+    assert!(fsck::check(false).unwrap().is_clean());
+    File::delete("/etc/config-v1").unwrap();
+    File::delete("/backup").unwrap();
+    ParvaFS::rename("/etc/again", "/again").unwrap();
+    assert_eq!(read_all("/again"), b"changed");
+
+    // A wrong link count is found and fixed
+    let node = ParvaFS::stat("/again").unwrap().node().unwrap();
+    let mut node = ParvaFS::Node::read(node).unwrap();
+    node.set_links(5);
+    node.write().unwrap();
+    let report = fsck::check(true).unwrap();
+    assert_eq!(report.problems, vec![fsck::Problem::LinkCount("/again".into(), 5, 1)]);
+    assert!(fsck::check(false).unwrap().is_clean());
+
+    let block_device = ParvaFS::unmount().unwrap();
+    ParvaFS::mount(block_device).unwrap();
+    assert_eq!(read_all("/conf/current-config"), b"version 2");
+    assert_eq!(ParvaFS::stat("/again").unwrap().links(), 1);
+    for path in &["/again", "/a", "/b", "/dangling", "/conf"] {
+        File::delete(path).unwrap();
+    }
+    Dir::delete_all("/etc").unwrap();
+    assert_eq!(BlockBitmap::free_count().unwrap(), free);
+    assert!(fsck::check(false).unwrap().is_clean());
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn directories_reuse_slots_and_shrink() {
+    let _lock = LOCK.lock();
+    ParvaFS::format(Box::new(MemDevice::new(BLOCKS))).unwrap();
+    let free = BlockBitmap::free_count().unwrap();
+    Dir::create("/busy").unwrap();
+    let empty = BlockBitmap::free_count().unwrap();
+
+    // 12 entries fill a block: 60 take 5, and moving 55 away leaves them deleted
+    for i in 0..60 {
+        File::create(&format!("/busy/file{:02}", i)).unwrap();
+    }
+    for i in 0..55 {
+        ParvaFS::rename(&format!("/busy/file{:02}", i), &format!("/file{:02}", i)).unwrap();
+    }
+    assert_eq!(Dir::open("/busy").unwrap().compact(), Ok(4));
+    assert_eq!(Dir::open("/busy").unwrap().compact(), Ok(0));
+    assert_eq!(names("/busy"), vec!["file55", "file56", "file57", "file58", "file59"]);
+
+    // A new name takes the slot of a deleted one where it fits
+    File::delete("/busy/file56").unwrap();
+    File::create("/busy/other").unwrap();
+    File::create("/busy/new-01").unwrap();
+    assert_eq!(names("/busy"), vec!["file55", "new-01", "file57", "file58", "file59", "other"]);
+
+    // Deleting shrinks the root back to one block
+    for i in 0..55 {
+        File::delete(&format!("/file{:02}", i)).unwrap();
+    }
+    assert_eq!(BlockBitmap::free_count().unwrap(), empty - 6);
+    assert!(fsck::check(false).unwrap().is_clean());
+    Dir::delete_all("/busy").unwrap();
+    assert_eq!(BlockBitmap::free_count().unwrap(), free);
+    ParvaFS::unmount().unwrap();
+}
+
+#[test]
+fn partitions_hold_separate_volumes() {
+    let _lock = LOCK.lock();
+    let disk = CrashDevice::new(8 * ALIGN as usize);
+    disk.data.lock().unwrap()[..4].copy_from_slice(b"boot");
+    let mut device = disk.clone();
+    assert!(partition::read(&mut device).unwrap().is_none());
+
+    // A 2 MiB partition, then the rest of the disk up to the backup table
+    let mut table = partition::create(&mut device, Scheme::Gpt, [1; 16]).unwrap();
+    let first = table.add(Some(2 * ALIGN), [2; 16]).unwrap();
+    let second = table.add(None, [3; 16]).unwrap();
+    table.write(&mut device).unwrap();
+    assert_eq!((first.index, first.start, first.count), (1, ALIGN, 2 * ALIGN));
+    assert_eq!((second.index, second.start, second.count), (2, 3 * ALIGN, 5 * ALIGN - 33));
+    assert_eq!(second.kind, Kind::Gpt(GPT_PARVAFS));
+    assert_eq!(second.kind.to_string(), "ParvaFS");
+    let efi = [0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b];
+    assert_eq!(Kind::Gpt(efi).to_string(), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+    assert_eq!(table.add(Some(1), [4; 16]).err(), Some(FsError::NoSpace));
+
+    ParvaFS::format(Box::new(PartitionDevice::new(Box::new(disk.clone()), &first))).unwrap();
+    File::create("/first").unwrap().write(b"first").unwrap();
+    let volume = Volume::format(Box::new(PartitionDevice::new(Box::new(disk.clone()), &second))).unwrap();
+    volume.run(|| File::create("/second")?.write(b"second")).unwrap();
+    volume.run(ParvaFS::unmount).unwrap();
+    ParvaFS::unmount().unwrap();
+    assert_eq!(&disk.data.lock().unwrap()[..4], b"boot");
+
+    // The backup table is read when the primary one is damaged, and is not when both are
+    disk.data.lock().unwrap()[512 + 60] ^= 1;
+    let table = partition::read(&mut device).unwrap().unwrap();
+    assert_eq!(table.partitions(), vec![first.clone(), second.clone()]);
+    let last = 8 * ALIGN as usize - 1;
+    disk.data.lock().unwrap()[last * 512 + 60] ^= 1;
+    assert_eq!(partition::read(&mut device).err(), Some(FsError::BadTable));
+    disk.data.lock().unwrap()[512 + 60] ^= 1;
+
+    let mut table = partition::read(&mut device).unwrap().unwrap();
+    assert_eq!(table.remove(1), Ok(first.clone()));
+    assert_eq!(table.remove(1).err(), Some(FsError::NotFound));
+    table.write(&mut device).unwrap();
+    assert_eq!(partition::read(&mut device).unwrap().unwrap().partitions(), vec![second.clone()]);
+    ParvaFS::mount(Box::new(PartitionDevice::new(Box::new(disk.clone()), &second))).unwrap();
+    assert_eq!(names("/"), vec!["second"]);
+    assert!(fsck::check(false).unwrap().is_clean());
+    ParvaFS::unmount().unwrap();
+
+    // An MBR takes the place of the GPT, with the same alignment
+    let mut table = partition::create(&mut device, Scheme::Mbr, [0; 16]).unwrap();
+    let first = table.add(Some(ALIGN), [0; 16]).unwrap();
+    table.write(&mut device).unwrap();
+    assert_eq!((first.index, first.start, first.count), (1, ALIGN, ALIGN));
+    let table = partition::read(&mut device).unwrap().unwrap();
+    assert_eq!(table.scheme, Scheme::Mbr);
+    assert_eq!(table.partitions(), vec![first]);
+    assert_eq!(disk.data.lock().unwrap()[446 + 4], MBR_PARVAFS);
+    assert_eq!(&disk.data.lock().unwrap()[..4], b"boot");
+}